@@ -1,9 +1,13 @@
 use std::fs::File;
+use std::io::{BufReader, BufWriter};
 
-fn main() -> Result<(), std::io::Error> {
-    let mut decoder = zen_jpeg::Decoder::new(File::open("./test-images/profile.jpg")?);
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut decoder =
+        zen_jpeg::Decoder::new(BufReader::new(File::open("./test-images/profile.jpg")?));
 
-    decoder.decode().unwrap();
+    let image = decoder.decode().unwrap();
+
+    image.write_pnm(BufWriter::new(File::create("out.ppm")?))?;
 
     Ok(())
 }
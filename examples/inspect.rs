@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use zen_jpeg::segment::{JpegMarker, SegmentContents};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "./test-images/profile.jpg".to_string());
+
+    let mut decoder = zen_jpeg::Decoder::new(BufReader::new(File::open(path)?));
+
+    for segment in decoder.inspect()? {
+        println!(
+            "0x{:08x}  {} ({} bytes)",
+            segment.offset,
+            segment.marker.segment_name(),
+            segment.length
+        );
+
+        if let JpegMarker::App(n) = segment.marker {
+            println!("  APP{n}");
+        }
+
+        match segment.contents {
+            SegmentContents::Jfif(jfif) => {
+                println!("  Version:   {}.{}", jfif.version.0, jfif.version.1);
+                println!("  Units:     {}", jfif.units);
+                println!("  Density:   {}x{}", jfif.x_density, jfif.y_density);
+                println!(
+                    "  Thumbnail: {}x{}",
+                    jfif.thumbnail_width, jfif.thumbnail_height
+                );
+            }
+            SegmentContents::Adobe(adobe) => {
+                println!("  Version:   {}", adobe.version);
+                println!("  Transform: {}", adobe.transform);
+            }
+            SegmentContents::QuantizationTables(tables) => {
+                for table in tables {
+                    println!("  Table {} ({}-bit):", table.index, table.precision);
+
+                    for row in table.values.chunks_exact(8) {
+                        println!("    {row:>4?}");
+                    }
+                }
+            }
+            SegmentContents::HuffmanTables(tables) => {
+                for table in tables {
+                    println!(
+                        "  {:?} table {}, {} symbols",
+                        table.class,
+                        table.index,
+                        table.symbols.len()
+                    );
+                }
+            }
+            SegmentContents::ArithmeticConditioning(conditioning) => {
+                for c in conditioning {
+                    println!("  {:?} conditioning {}: {}", c.class, c.index, c.value);
+                }
+            }
+            SegmentContents::RestartInterval(interval) => {
+                println!("  Restart every {interval} MCUs");
+            }
+            SegmentContents::NumberOfLines(lines) => {
+                println!("  {lines} lines");
+            }
+            SegmentContents::Frame(frame) => {
+                println!("  {}-bit precision", frame.precision);
+                println!("  Resolution: {}x{} px", frame.width, frame.height);
+
+                for c in frame.components {
+                    println!(
+                        "  Component {}: sampling {}x{}, quant table {}",
+                        c.id, c.h_sampling, c.v_sampling, c.quant_table
+                    );
+                }
+            }
+            SegmentContents::Scan(scan) => {
+                println!(
+                    "  Spectral selection {}..={}, approximation {}/{}",
+                    scan.spectral_start, scan.spectral_end, scan.approx_high, scan.approx_low
+                );
+
+                for c in scan.components {
+                    println!(
+                        "  Component {}: DC table {}, AC table {}",
+                        c.id, c.dc_table, c.ac_table
+                    );
+                }
+            }
+            SegmentContents::Comment(comment) => {
+                println!("  {}", String::from_utf8_lossy(&comment));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
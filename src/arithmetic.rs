@@ -0,0 +1,432 @@
+//! Block decoding for arithmetic-coded frames (SOF9, SOF10)
+//!
+//! Coefficients are decoded one binary decision at a time with the
+//! QM-coder. Each decision uses a statistics bin that adapts to the data,
+//! selected by the coefficient index and the values decoded before it
+//! (Annex F.1.4 and G.1.3).
+//!
+//! The statistics model of lossless frames (SOF11, Annex H.1.4.3) is not
+//! implemented, so they are rejected before their first scan.
+
+use std::io::BufRead;
+
+use crate::bitstream::BitReader;
+use crate::ec::{ArithmeticDecoder, FIXED_BIN};
+use crate::error::DecodeError;
+use crate::segment::{ArithmeticConditioning, TableClass};
+
+/// Statistics bins per DC table
+const DC_BINS: usize = 64;
+/// Statistics bins per AC table
+const AC_BINS: usize = 256;
+
+/// Conditioning of the statistics of every table slot, set by DAC segments
+#[derive(Copy, Clone, Debug)]
+pub struct Conditioning {
+    /// Bounds L and U of the small DC difference category
+    dc: [(u8, u8); 4],
+    /// Kx, the last coefficient that uses the low frequency magnitude bins
+    ac: [u8; 4],
+}
+
+impl Default for Conditioning {
+    fn default() -> Self {
+        Self {
+            dc: [(0, 1); 4],
+            ac: [5; 4],
+        }
+    }
+}
+
+impl Conditioning {
+    pub fn set(&mut self, conditioning: &ArithmeticConditioning) {
+        let index = conditioning.index as usize;
+        let value = conditioning.value;
+
+        match conditioning.class {
+            TableClass::Dc => self.dc[index] = (value & 0xf, value >> 4),
+            TableClass::Ac => self.ac[index] = value,
+        }
+    }
+}
+
+/// Arithmetic decoding state of a scan, reset at every restart marker
+pub struct ArithmeticState {
+    decoder: ArithmeticDecoder,
+    conditioning: Conditioning,
+    dc_stats: [[u8; DC_BINS]; 4],
+    ac_stats: [[u8; AC_BINS]; 4],
+    /// Conditioning category of the last DC difference of every component
+    /// in the scan, the offset of its first statistics bin
+    dc_context: Vec<usize>,
+    fixed_bin: u8,
+}
+
+impl ArithmeticState {
+    pub fn new(num_components: usize, conditioning: Conditioning) -> Self {
+        Self {
+            decoder: ArithmeticDecoder::new(),
+            conditioning,
+            dc_stats: [[0; DC_BINS]; 4],
+            ac_stats: [[0; AC_BINS]; 4],
+            dc_context: vec![0; num_components],
+            fixed_bin: FIXED_BIN,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.decoder = ArithmeticDecoder::new();
+        self.dc_stats = [[0; DC_BINS]; 4];
+        self.ac_stats = [[0; AC_BINS]; 4];
+        self.dc_context.fill(0);
+    }
+}
+
+/// Table slots and position in the scan of the component a block belongs to
+#[derive(Copy, Clone)]
+pub struct BlockTables {
+    /// Index of the component in the scan
+    pub component: usize,
+    pub dc_table: usize,
+    pub ac_table: usize,
+}
+
+/// Decodes the remaining magnitude category decisions of a value whose
+/// category is at least `m`, starting at bin `st` (F.2.4.3.1). Returns the
+/// category and the bin of the last decision.
+fn decode_category<R: BufRead>(
+    decoder: &mut ArithmeticDecoder,
+    bitreader: &mut BitReader<R>,
+    bins: &mut [u8],
+    (mut m, mut st): (i32, usize),
+) -> Result<(i32, usize), DecodeError> {
+    while decoder.decode(bitreader, &mut bins[st]) {
+        m <<= 1;
+
+        if m == 0x8000 {
+            return Err(DecodeError::CorruptData("arithmetic magnitude overflow"));
+        }
+
+        st += 1;
+    }
+
+    Ok((m, st))
+}
+
+/// Decodes the bits of a value below its magnitude category `m`
+/// (F.2.4.3.2). Returns the magnitude of the value minus one.
+fn decode_bits<R: BufRead>(
+    decoder: &mut ArithmeticDecoder,
+    bitreader: &mut BitReader<R>,
+    bin: &mut u8,
+    m: i32,
+) -> i32 {
+    let mut v = m;
+
+    let mut bit = m >> 1;
+    while bit != 0 {
+        if decoder.decode(bitreader, bin) {
+            v |= bit;
+        }
+
+        bit >>= 1;
+    }
+
+    v
+}
+
+/// Decodes the difference of a DC coefficient to its prediction
+fn decode_dc_diff<R: BufRead>(
+    state: &mut ArithmeticState,
+    bitreader: &mut BitReader<R>,
+    tables: BlockTables,
+) -> Result<i32, DecodeError> {
+    let decoder = &mut state.decoder;
+    let bins = &mut state.dc_stats[tables.dc_table];
+    let context = &mut state.dc_context[tables.component];
+
+    let st = *context;
+
+    if !decoder.decode(bitreader, &mut bins[st]) {
+        *context = 0;
+        return Ok(0);
+    }
+
+    let sign = decoder.decode(bitreader, &mut bins[st + 1]);
+    let st = st + 2 + sign as usize;
+
+    // categories past the first share the bins starting at X1
+    let (m, st) = match decoder.decode(bitreader, &mut bins[st]) {
+        false => (0, st),
+        true => decode_category(decoder, bitreader, bins, (1, 20))?,
+    };
+
+    set_dc_context(context, state.conditioning.dc[tables.dc_table], m, sign);
+
+    // the magnitude bits use the bin 14 after the last category decision
+    let v = decode_bits(decoder, bitreader, &mut bins[st + 14], m) + 1;
+
+    Ok(if sign { -v } else { v })
+}
+
+/// Classifies a DC difference as zero, small or large (F.1.4.4.1.2), which
+/// selects the bins for the difference of the next block
+fn set_dc_context(context: &mut usize, (l, u): (u8, u8), m: i32, sign: bool) {
+    *context = if m < (1 << l) >> 1 {
+        0
+    } else if m > (1 << u) >> 1 {
+        12 + 4 * sign as usize
+    } else {
+        4 + 4 * sign as usize
+    };
+}
+
+/// Decodes the first scan of the DC coefficient, or the DC coefficient of a
+/// sequential block
+pub fn decode_dc_first<R: BufRead>(
+    block: &mut [i16; 64],
+    state: &mut ArithmeticState,
+    bitreader: &mut BitReader<R>,
+    tables: BlockTables,
+    dc_pred: &mut i16,
+    approx_low: u8,
+) -> Result<(), DecodeError> {
+    let diff = decode_dc_diff(state, bitreader, tables)?;
+
+    *dc_pred = dc_pred.wrapping_add(diff as i16);
+
+    block[0] = dc_pred.wrapping_shl(approx_low as u32);
+
+    Ok(())
+}
+
+/// Refines the DC coefficient by one bit
+pub fn decode_dc_refine<R: BufRead>(
+    block: &mut [i16; 64],
+    state: &mut ArithmeticState,
+    bitreader: &mut BitReader<R>,
+    approx_low: u8,
+) -> Result<(), DecodeError> {
+    if state.decoder.decode(bitreader, &mut state.fixed_bin) {
+        block[0] |= 1 << approx_low;
+    }
+
+    Ok(())
+}
+
+/// First scan of the AC coefficients `start..=end`, or all AC coefficients
+/// of a sequential block
+pub fn decode_ac_first<R: BufRead>(
+    block: &mut [i16; 64],
+    state: &mut ArithmeticState,
+    bitreader: &mut BitReader<R>,
+    tables: BlockTables,
+    (start, end): (u8, u8),
+    approx_low: u8,
+) -> Result<(), DecodeError> {
+    let decoder = &mut state.decoder;
+    let bins = &mut state.ac_stats[tables.ac_table];
+    let kx = state.conditioning.ac[tables.ac_table] as usize;
+
+    let (start, end) = (start as usize, end as usize);
+
+    let mut k = start;
+
+    while k <= end {
+        let mut st = 3 * (k - 1);
+
+        // end of block
+        if decoder.decode(bitreader, &mut bins[st]) {
+            break;
+        }
+
+        // skip zero coefficients
+        while !decoder.decode(bitreader, &mut bins[st + 1]) {
+            st += 3;
+            k += 1;
+
+            if k > end {
+                return Err(DecodeError::CorruptData("AC run past end of band"));
+            }
+        }
+
+        let sign = decoder.decode(bitreader, &mut state.fixed_bin);
+
+        st += 2;
+
+        // the second category decision uses the same bin as the first, the
+        // rest depend on the frequency of the coefficient
+        let (m, st) = if !decoder.decode(bitreader, &mut bins[st]) {
+            (0, st)
+        } else if !decoder.decode(bitreader, &mut bins[st]) {
+            (1, st)
+        } else {
+            let x_bins = if k <= kx { 189 } else { 217 };
+            decode_category(decoder, bitreader, bins, (2, x_bins))?
+        };
+
+        let v = decode_bits(decoder, bitreader, &mut bins[st + 14], m) + 1;
+        let v = if sign { -v } else { v };
+
+        block[k] = (v as i16).wrapping_shl(approx_low as u32);
+
+        k += 1;
+    }
+
+    Ok(())
+}
+
+/// Refines the AC coefficients `start..=end` by one bit
+pub fn decode_ac_refine<R: BufRead>(
+    block: &mut [i16; 64],
+    state: &mut ArithmeticState,
+    bitreader: &mut BitReader<R>,
+    tables: BlockTables,
+    (start, end): (u8, u8),
+    approx_low: u8,
+) -> Result<(), DecodeError> {
+    let decoder = &mut state.decoder;
+    let bins = &mut state.ac_stats[tables.ac_table];
+
+    let (start, end) = (start as usize, end as usize);
+
+    let p1 = 1 << approx_low;
+
+    // coefficients past the last nonzero one of the previous scans can't
+    // be followed by an end of block decision
+    let last_nonzero = (1..=end).rev().find(|&k| block[k] != 0).unwrap_or(0);
+
+    let mut k = start;
+
+    while k <= end {
+        let mut st = 3 * (k - 1);
+
+        if k > last_nonzero && decoder.decode(bitreader, &mut bins[st]) {
+            break;
+        }
+
+        loop {
+            let coeff = &mut block[k];
+
+            if *coeff != 0 {
+                // correction bit
+                if decoder.decode(bitreader, &mut bins[st + 2]) {
+                    if *coeff < 0 {
+                        *coeff = coeff.wrapping_sub(p1);
+                    } else {
+                        *coeff = coeff.wrapping_add(p1);
+                    }
+                }
+
+                break;
+            }
+
+            // newly nonzero coefficient
+            if decoder.decode(bitreader, &mut bins[st + 1]) {
+                *coeff = if decoder.decode(bitreader, &mut state.fixed_bin) {
+                    -p1
+                } else {
+                    p1
+                };
+
+                break;
+            }
+
+            st += 3;
+            k += 1;
+
+            if k > end {
+                return Err(DecodeError::CorruptData("AC run past end of band"));
+            }
+        }
+
+        k += 1;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLES: BlockTables = BlockTables {
+        component: 0,
+        dc_table: 0,
+        ac_table: 0,
+    };
+
+    #[test]
+    fn sets_conditioning_of_table_slots() {
+        let mut conditioning = Conditioning::default();
+
+        conditioning.set(&ArithmeticConditioning {
+            class: TableClass::Dc,
+            index: 1,
+            value: 0x52,
+        });
+        conditioning.set(&ArithmeticConditioning {
+            class: TableClass::Ac,
+            index: 3,
+            value: 20,
+        });
+
+        assert_eq!(conditioning.dc, [(0, 1), (2, 5), (0, 1), (0, 1)]);
+        assert_eq!(conditioning.ac, [5, 5, 5, 20]);
+    }
+
+    #[test]
+    fn classifies_dc_differences() {
+        let context = |bounds, m, sign| {
+            let mut context = usize::MAX;
+            set_dc_context(&mut context, bounds, m, sign);
+            context
+        };
+
+        // with the default bounds, differences up to 2 are small
+        assert_eq!(context((0, 1), 0, false), 4);
+        assert_eq!(context((0, 1), 1, true), 8);
+        assert_eq!(context((0, 1), 2, false), 12);
+        assert_eq!(context((0, 1), 2, true), 16);
+
+        // differences below 2^(L - 1) count as zero
+        assert_eq!(context((2, 3), 1, true), 0);
+        assert_eq!(context((2, 3), 2, true), 8);
+        assert_eq!(context((2, 3), 8, false), 12);
+    }
+
+    #[test]
+    fn decodes_first_decisions_of_zero_data() {
+        let mut data: &[u8] = &[0; 8];
+        let mut bitreader = BitReader::new(&mut data);
+        let mut state = ArithmeticState::new(1, Conditioning::default());
+
+        let mut block = [0; 64];
+        let mut dc_pred = 3;
+
+        // the first decision leaves an interval of 0x10000 - 0x5a1d, which
+        // still decodes the more probable symbol 0, a difference of zero,
+        // without changing the estimate
+        decode_dc_first(
+            &mut block,
+            &mut state,
+            &mut bitreader,
+            TABLES,
+            &mut dc_pred,
+            2,
+        )
+        .unwrap();
+
+        assert_eq!(dc_pred, 3);
+        assert_eq!(block[0], 12);
+        assert_eq!(state.dc_stats[0][0], 0);
+
+        // the second one leaves 0x4bc6, less than the estimate, so the
+        // conditional exchange decodes 1, the end of the block, and the
+        // more probable symbol of the bin switches
+        decode_ac_first(&mut block, &mut state, &mut bitreader, TABLES, (1, 63), 0).unwrap();
+
+        assert_eq!(block[1..], [0; 63]);
+        assert_eq!(state.ac_stats[0][0], 0x80 | 1);
+    }
+}
@@ -1,9 +1,8 @@
-use std::fs::File;
 use std::io;
-use std::io::{BufReader, Read};
+use std::io::{BufRead, ErrorKind, Read};
 
 /// Reads unsigned short in big-endian format
-pub fn read_u16(reader: &mut BufReader<File>) -> io::Result<u16> {
+pub fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
     let mut buf = [0; 2];
     reader.read_exact(&mut buf)?;
 
@@ -11,37 +10,146 @@ pub fn read_u16(reader: &mut BufReader<File>) -> io::Result<u16> {
 }
 
 /// Reads byte
-pub fn read_u8(reader: &mut BufReader<File>) -> io::Result<u8> {
+pub fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
     let mut buf = [0];
     reader.read_exact(&mut buf)?;
 
     Ok(buf[0])
 }
 
-pub(crate) struct BitReader<'a> {
-    reader: &'a mut BufReader<File>,
+/// Advances the reader by `n` bytes without copying them anywhere
+pub fn skip_bytes<R: BufRead>(reader: &mut R, mut n: usize) -> io::Result<()> {
+    while n > 0 {
+        let available = reader.fill_buf()?.len();
+
+        if available == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        let amt = available.min(n);
+        reader.consume(amt);
+        n -= amt;
+    }
+
+    Ok(())
+}
+
+/// Reads the second byte of a marker, after its first 0xff. Any number of
+/// 0xff fill bytes may precede a marker.
+pub fn read_marker_byte<R: Read>(reader: &mut R) -> io::Result<u8> {
+    loop {
+        let byte = read_u8(reader)?;
+
+        if byte != 0xff {
+            return Ok(byte);
+        }
+    }
+}
+
+/// Reader that keeps track of how many bytes were consumed from the
+/// underlying reader, so errors can point at an offset in the file.
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, pos: 0 }
+    }
+
+    /// Number of bytes consumed so far
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+pub(crate) struct BitReader<'a, R> {
+    reader: &'a mut R,
     // cached bits
     bitbuf: u64,
     bitlen: u32,
+    /// Second byte of a marker found in the entropy-coded data. Nothing
+    /// past it is read until it is cleared.
+    marker: Option<u8>,
+    /// First error of the underlying reader other than the end of the
+    /// data. Like a marker, nothing past it is read.
+    error: Option<io::Error>,
 }
 
-impl<'a> BitReader<'a> {
-    pub fn new(reader: &'a mut BufReader<File>) -> Self {
+impl<'a, R: BufRead> BitReader<'a, R> {
+    pub fn new(reader: &'a mut R) -> Self {
         Self {
             reader,
             bitbuf: 0,
             bitlen: 0,
+            marker: None,
+            error: None,
         }
     }
 
-    fn byte_refill(&mut self) -> Option<u8> {
+    /// Returns the value of a read, or keeps its error and returns `None`.
+    /// Only the end of the data is padded with zeros, other errors are
+    /// returned by [`BitReader::take_error`].
+    fn check<T>(&mut self, result: io::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                if e.kind() != ErrorKind::UnexpectedEof {
+                    self.error.get_or_insert(e);
+                }
+
+                None
+            }
+        }
+    }
+
+    /// Returns the error of the underlying reader that ended the data, if
+    /// there was one
+    pub fn take_error(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads the next byte of entropy-coded data, with stuffed zero bytes
+    /// removed. Returns `None` at a marker, at the end of the data or after
+    /// an error.
+    pub fn byte_refill(&mut self) -> Option<u8> {
+        if self.marker.is_some() || self.error.is_some() {
+            return None;
+        }
+
         // skip over 0x00 in 0xff00 found in bitstream
-        let new_byte = read_u8(self.reader).ok()?;
+        let new_byte = read_u8(self.reader);
+        let new_byte = self.check(new_byte)?;
 
         if new_byte == 0xff {
-            let next_byte = read_u8(self.reader).ok()?;
+            let next_byte = read_marker_byte(self.reader);
+            let next_byte = self.check(next_byte)?;
 
             if next_byte != 0x00 {
+                self.marker = Some(next_byte);
                 return None;
             }
         }
@@ -49,66 +157,228 @@ impl<'a> BitReader<'a> {
         Some(new_byte)
     }
 
-    // Only use for reading start of scan data
-    // Not a general purpose get_bit function
-    #[allow(unused)]
-    pub fn get_bit(&mut self) -> Option<bool> {
-        // refill buffer
-        if self.bitlen == 0 {
-            // TODO is there a subtle bug here?
-            // like we should refill at least once and return
-            // an error if the first refill failed
-            while let Some(byte) = self.byte_refill() {
-                self.bitbuf |= (byte as u64).rotate_right(u8::BITS + self.bitlen);
-                self.bitlen += 8;
-                if self.bitlen == 64 - 16 {
-                    break;
-                }
+    /// Discards the cached bits and skips ahead to the next marker. The
+    /// marker is not cleared, so it is returned again until
+    /// [`BitReader::clear_marker`] is called.
+    pub fn next_marker(&mut self) -> io::Result<u8> {
+        self.bitbuf = 0;
+        self.bitlen = 0;
+
+        loop {
+            self.take_error()?;
+
+            if let Some(marker) = self.marker {
+                return Ok(marker);
+            }
+
+            // the cached bits are discarded anyway, so everything up to
+            // the next 0xff is skipped at once
+            let buf = self.reader.fill_buf()?;
+            let n = buf.iter().position(|&b| b == 0xff).unwrap_or(buf.len());
+            self.reader.consume(n);
+
+            if self.byte_refill().is_none() && self.marker.is_none() {
+                return Err(ErrorKind::UnexpectedEof.into());
             }
         }
+    }
+
+    /// Whether the entropy-coded data ends before the next whole byte, at
+    /// the end of the scan. Restart markers don't end the data.
+    pub fn at_end(&mut self) -> bool {
+        if self.bitlen < 8 {
+            self.refill();
+        }
 
-        self.bitlen -= 1;
-        let bit = self.bitbuf >> 63 != 0;
-        self.bitbuf <<= 1;
-        Some(bit)
+        self.bitlen < 8 && !matches!(self.marker, Some(0xd0..=0xd7))
     }
 
-    pub fn peek_bits<const BITS: u32>(&mut self) -> Option<u16> {
-        assert!(BITS <= 16);
+    /// Resumes reading the entropy-coded data after a restart marker
+    pub fn clear_marker(&mut self) {
+        self.marker = None;
+    }
 
-        while self.bitlen < BITS {
-            // pad with zeroes if nothing is left
-            let byte = match self.byte_refill() {
-                Some(x) => x,
-                None => break,
+    /// Fills the cache with at least 57 bits, or with all the bits left
+    /// before a marker or the end of the data. The bits after the cached
+    /// ones are always zero, which pads the data with zeros at its end.
+    fn refill(&mut self) {
+        // without any 0xff there are no markers or stuffed bytes in the
+        // next 8 bytes, so they can be copied at once
+        if self.marker.is_none() && self.error.is_none() {
+            let buf = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                // read again byte by byte below
+                Err(e) if e.kind() == ErrorKind::Interrupted => &[],
+                Err(e) => {
+                    self.error = Some(e);
+                    return;
+                }
             };
-            self.bitbuf |= (byte as u64).rotate_right(8) >> self.bitlen;
+
+            if let Some(word) = buf.get(..8) {
+                let word = u64::from_be_bytes(word.try_into().unwrap());
+
+                if !has_ff_byte(word) {
+                    let n = (64 - self.bitlen) / 8;
+
+                    self.bitbuf |= (word >> (64 - 8 * n)) << (64 - 8 * n - self.bitlen);
+                    self.bitlen += 8 * n;
+                    self.reader.consume(n as usize);
+                    return;
+                }
+            }
+        }
+
+        while self.bitlen <= 56 {
+            let Some(byte) = self.byte_refill() else {
+                break;
+            };
+
+            self.bitbuf |= (byte as u64) << (56 - self.bitlen);
             self.bitlen += 8;
         }
+    }
 
-        let code = (self.bitbuf >> (64 - BITS)) as u16;
+    /// Returns the next `BITS` bits without consuming them, padded with
+    /// zeros past the end of the data
+    pub fn peek_bits<const BITS: u32>(&mut self) -> u16 {
+        assert!(BITS > 0 && BITS <= 16);
 
-        Some(code)
+        if self.bitlen < BITS {
+            self.refill();
+        }
+
+        (self.bitbuf >> (64 - BITS)) as u16
+    }
+
+    /// Number of bits read from the data but not consumed yet
+    pub fn cached_bits(&self) -> u32 {
+        self.bitlen
     }
 
     pub fn consume_bits(&mut self, bits: u32) {
         self.bitbuf <<= bits;
-        self.bitlen -= bits;
+        // peek_bits pads with zeroes past the end of the data,
+        // which may leave fewer cached bits than were consumed
+        self.bitlen = self.bitlen.saturating_sub(bits);
     }
 
+    /// Returns `None` if the data ends before `bits` bits
     pub fn get_n_bits(&mut self, bits: u32) -> Option<u16> {
         debug_assert!(bits <= 16);
 
-        // TODO maybe refill to max size here as well
-        while self.bitlen < bits {
-            let byte = self.byte_refill()?;
-            self.bitbuf |= (byte as u64).rotate_right(8) >> self.bitlen;
-            self.bitlen += 8;
+        if bits == 0 {
+            return Some(0);
         }
 
-        let code = self.bitbuf.rotate_left(bits) as u16;
+        if self.bitlen < bits {
+            self.refill();
+
+            if self.bitlen < bits {
+                return None;
+            }
+        }
+
+        let code = (self.bitbuf >> (64 - bits)) as u16;
         self.bitbuf <<= bits;
         self.bitlen -= bits;
         Some(code)
     }
 }
+
+/// Whether any byte of `word` is 0xff
+fn has_ff_byte(word: u64) -> bool {
+    const LO: u64 = 0x0101_0101_0101_0101;
+    const HI: u64 = 0x8080_8080_8080_8080;
+
+    // the high bit of a byte of !word - 1 is only set along with the high
+    // bit of word itself if the byte of !word is zero
+    (!word).wrapping_sub(LO) & word & HI != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reader that fails once its data is read, instead of ending
+    struct Failing<'a>(&'a [u8]);
+
+    impl Read for Failing<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.fill_buf()?.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.consume(n);
+
+            Ok(n)
+        }
+    }
+
+    impl BufRead for Failing<'_> {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            match self.0 {
+                [] => Err(ErrorKind::TimedOut.into()),
+                data => Ok(data),
+            }
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.0 = &self.0[amt..];
+        }
+    }
+
+    #[test]
+    fn pads_the_end_of_the_data_with_zeros() {
+        let mut reader: &[u8] = &[0xab];
+        let mut bitreader = BitReader::new(&mut reader);
+
+        assert_eq!(bitreader.peek_bits::<16>(), 0xab00);
+        assert_eq!(bitreader.get_n_bits(16), None);
+        assert!(bitreader.take_error().is_ok());
+    }
+
+    #[test]
+    fn keeps_errors_other_than_the_end_of_the_data() {
+        let mut reader = Failing(&[0x12, 0x34]);
+        let mut bitreader = BitReader::new(&mut reader);
+
+        assert_eq!(bitreader.get_n_bits(16), Some(0x1234));
+        assert_eq!(bitreader.get_n_bits(8), None);
+
+        let error = bitreader.take_error().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn finds_the_next_marker() {
+        let mut reader: &[u8] = &[0x12, 0xff, 0x00, 0x34, 0xff, 0xff, 0xd3, 0x56];
+        let mut bitreader = BitReader::new(&mut reader);
+
+        assert_eq!(bitreader.get_n_bits(8), Some(0x12));
+        assert_eq!(bitreader.next_marker().unwrap(), 0xd3);
+
+        bitreader.clear_marker();
+        assert_eq!(bitreader.get_n_bits(8), Some(0x56));
+    }
+
+    #[test]
+    fn finds_ff_at_every_position() {
+        for i in 0..8 {
+            assert!(has_ff_byte(0xff << (8 * i)), "0xff in byte {i}");
+            assert!(has_ff_byte(0x0102_0304_0506_0708 | 0xff << (8 * i)));
+        }
+    }
+
+    #[test]
+    fn ignores_near_misses() {
+        assert!(!has_ff_byte(0));
+        assert!(!has_ff_byte(0xfefe_fefe_fefe_fefe));
+        assert!(!has_ff_byte(0x7f7f_7f7f_7f7f_7f7f));
+        assert!(!has_ff_byte(0xfe7f_fe7f_fe7f_fe7f));
+        assert!(!has_ff_byte(0x7ffe_7ffe_7ffe_7ffe));
+
+        for i in 0..8 {
+            assert!(!has_ff_byte(0xfe << (8 * i)), "0xfe in byte {i}");
+            assert!(!has_ff_byte(0x7f << (8 * i)), "0x7f in byte {i}");
+        }
+    }
+}
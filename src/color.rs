@@ -0,0 +1,172 @@
+/// Color space of the components of an image
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    Gray,
+    /// Luma and chroma, the color space of JFIF files
+    YCbCr,
+    /// Red, green and blue components coded without any transform
+    Rgb,
+    Cmyk,
+    /// Luma and chroma of the inverted C, M and Y components, and K
+    Ycck,
+}
+
+impl ColorSpace {
+    /// Number of components of an image in this color space
+    pub fn components(self) -> usize {
+        match self {
+            ColorSpace::Gray => 1,
+            ColorSpace::YCbCr | ColorSpace::Rgb => 3,
+            ColorSpace::Cmyk | ColorSpace::Ycck => 4,
+        }
+    }
+}
+
+#[inline(always)]
+#[allow(clippy::suboptimal_flops)]
+pub fn ycbcr_to_rgb(y: f32, cb: f32, cr: f32, center: f32, max: f32) -> [u16; 3] {
+    // let r = f32::mul_add(1.402, cr - center, y);
+    // let g = f32::mul_add(-0.71414, cr - center, f32::mul_add(-0.34414, cb - center, y));
+    // let b = f32::mul_add(1.772, cb - center, y);
+
+    let r = 1.402 * (cr - center) + y;
+    let g = -0.71414 * (cr - center) + -0.34414 * (cb - center) + y;
+    let b = 1.772 * (cb - center) + y;
+
+    let r = r.clamp(0.0, max) as u16;
+    let g = g.clamp(0.0, max) as u16;
+    let b = b.clamp(0.0, max) as u16;
+
+    [r, g, b]
+}
+
+/// Converts full resolution Y, Cb and Cr planes with a row stride of
+/// `stride` samples to interleaved RGB, cropped to `w` by `h` pixels.
+/// Samples have `precision` bits.
+#[inline(never)]
+pub fn to_rgb(
+    (w, h): (usize, usize),
+    stride: usize,
+    planes: [&[u16]; 3],
+    precision: u8,
+    buf: &mut [u16],
+) {
+    let [y_plane, cb_plane, cr_plane] = planes;
+
+    // chroma is centered around half of the sample range
+    let center = (1 << (precision - 1)) as f32;
+    let max = ((1 << precision) - 1) as f32;
+
+    for (y, row) in buf.chunks_exact_mut(3 * w).take(h).enumerate() {
+        let y_row = &y_plane[y * stride..][..w];
+        let cb_row = &cb_plane[y * stride..][..w];
+        let cr_row = &cr_plane[y * stride..][..w];
+
+        for (x, px) in row.chunks_exact_mut(3).enumerate() {
+            let rgb = ycbcr_to_rgb(
+                y_row[x] as f32,
+                cb_row[x] as f32,
+                cr_row[x] as f32,
+                center,
+                max,
+            );
+
+            px.copy_from_slice(&rgb);
+        }
+    }
+}
+
+/// Interleaves the first `w` samples of the first `h` rows of full
+/// resolution planes with a row stride of `stride` samples
+pub fn interleave((w, h): (usize, usize), stride: usize, planes: &[&[u16]], buf: &mut [u16]) {
+    let n = planes.len();
+
+    for (y, row) in buf.chunks_exact_mut(n * w).take(h).enumerate() {
+        for (i, plane) in planes.iter().enumerate() {
+            let src = &plane[y * stride..][..w];
+
+            for (px, &sample) in row.chunks_exact_mut(n).zip(src) {
+                px[i] = sample;
+            }
+        }
+    }
+}
+
+/// Copies the first `w` samples of the first `h` rows of a plane with a
+/// row stride of `stride` samples
+pub fn to_gray((w, h): (usize, usize), stride: usize, plane: &[u16], buf: &mut [u16]) {
+    for (y, row) in buf.chunks_exact_mut(w).take(h).enumerate() {
+        row.copy_from_slice(&plane[y * stride..][..w]);
+    }
+}
+
+/// Expands a grayscale plane to interleaved RGB, with R = G = B
+pub fn gray_to_rgb((w, h): (usize, usize), stride: usize, plane: &[u16], buf: &mut [u16]) {
+    for (y, row) in buf.chunks_exact_mut(3 * w).take(h).enumerate() {
+        for (px, &luma) in row.chunks_exact_mut(3).zip(&plane[y * stride..][..w]) {
+            px.fill(luma);
+        }
+    }
+}
+
+/// Converts full resolution planes of a four component image to
+/// interleaved CMYK, where 0 is no ink. YCCK images code the Y, Cb and Cr
+/// of the inverted C, M and Y components, and K as is. Images with an
+/// Adobe header store inverted values.
+pub fn to_cmyk(
+    (w, h): (usize, usize),
+    stride: usize,
+    planes: [&[u16]; 4],
+    (ycck, inverted): (bool, bool),
+    precision: u8,
+    buf: &mut [u16],
+) {
+    let center = (1 << (precision - 1)) as f32;
+    let max = ((1u32 << precision) - 1) as u16;
+
+    for (y, row) in buf.chunks_exact_mut(4 * w).take(h).enumerate() {
+        let rows = planes.map(|plane| &plane[y * stride..][..w]);
+
+        for (x, px) in row.chunks_exact_mut(4).enumerate() {
+            let [c, m, y, k] = rows.map(|row| row[x]);
+
+            let [c, m, y] = match ycck {
+                true => {
+                    ycbcr_to_rgb(c as f32, m as f32, y as f32, center, max as f32).map(|s| max - s)
+                }
+                false => [c, m, y],
+            };
+
+            let cmyk = match inverted {
+                true => [c, m, y, k].map(|s| max - s),
+                false => [c, m, y, k],
+            };
+
+            px.copy_from_slice(&cmyk);
+        }
+    }
+}
+
+/// Converts interleaved CMYK to interleaved RGB, without any color
+/// management
+pub fn cmyk_to_rgb(cmyk: &[u16], precision: u8, buf: &mut [u16]) {
+    let max = (1u32 << precision) - 1;
+
+    for (px, rgb) in cmyk.chunks_exact(4).zip(buf.chunks_exact_mut(3)) {
+        let k = max - px[3] as u32;
+
+        for (out, &ink) in rgb.iter_mut().zip(&px[..3]) {
+            *out = (((max - ink as u32) * k + max / 2) / max) as u16;
+        }
+    }
+}
+
+/// Converts interleaved RGB to luma
+pub fn rgb_to_gray(rgb: &[u16], buf: &mut [u16]) {
+    for (px, out) in rgb.chunks_exact(3).zip(buf.iter_mut()) {
+        let [r, g, b] = [px[0], px[1], px[2]].map(u32::from);
+
+        // 0.299, 0.587 and 0.114 in 16-bit fixed point
+        *out = ((19595 * r + 38470 * g + 7471 * b + 32768) >> 16) as u16;
+    }
+}
@@ -16,6 +16,7 @@ fn transpose8x8(inm: &[f32; 64], outm: &mut [f32; 64]) {
     }
 }
 
+#[allow(clippy::excessive_precision, clippy::approx_constant)]
 const COS_TABLE: [f32; 64] = [
     0.707106781186547524400844362105,
     0.980785280403230449126182236134,
@@ -1,103 +1,21 @@
-use std::fmt::{Debug, Display};
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::mem::size_of;
+use std::io::{BufRead, ErrorKind};
 
-use crate::bitstream::{read_u16, read_u8, BitReader};
+use crate::arithmetic::{self, ArithmeticState, BlockTables, Conditioning};
+use crate::bitstream::{read_marker_byte, read_u16, BitReader, CountingReader};
+use crate::color::{
+    cmyk_to_rgb, gray_to_rgb, interleave, rgb_to_gray, to_cmyk, to_gray, to_rgb, ColorSpace,
+};
 use crate::dct::idct;
-use crate::ec::{sign_code, HuffmanTree};
-use crate::error::DecodeError;
-
-#[derive(Copy, Clone)]
-enum JpegMarker {
-    StartOfImage,
-    ApplicationDefaultHeader,
-    DefineQuantizationTable,
-    StartOfFrame,
-    DefineHuffmanTable,
-    StartOfScan,
-    EndOfImage,
-    PictInfo,
-    AdobeApp14,
-    Comment,
-    AppSeg1,
-    AppSeg2,
-}
-
-impl JpegMarker {
-    fn segment_name(self) -> &'static str {
-        match self {
-            JpegMarker::StartOfImage => "Start of Image",
-            JpegMarker::ApplicationDefaultHeader => "Application Default Header",
-            JpegMarker::DefineQuantizationTable => "Define Quantization Table",
-            JpegMarker::StartOfFrame => "Start of Frame",
-            JpegMarker::DefineHuffmanTable => "Define Huffman Table",
-            JpegMarker::StartOfScan => "Start of Scan",
-            JpegMarker::EndOfImage => "End of Image",
-            JpegMarker::PictInfo => "Picture Info",
-            JpegMarker::AdobeApp14 => "Adobe APP14",
-            JpegMarker::Comment => "Comment",
-            JpegMarker::AppSeg1 => "EXIF Metadata (Application Segment 1)",
-            JpegMarker::AppSeg2 => "ICC color profile, FlashPix",
-        }
-    }
-}
-
-struct InvalidJpegMarker {
-    marker: u16,
-}
-
-impl Debug for InvalidJpegMarker {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "invalid marker, found 0x{:x}", self.marker)
-    }
-}
-
-impl TryFrom<u16> for JpegMarker {
-    type Error = InvalidJpegMarker;
-
-    fn try_from(value: u16) -> Result<Self, Self::Error> {
-        let [high, low] = value.to_be_bytes();
-
-        if high != 0xff {
-            return Err(InvalidJpegMarker { marker: value });
-        }
-
-        match low {
-            0xd8 => Ok(JpegMarker::StartOfImage),
-            0xe0 => Ok(JpegMarker::ApplicationDefaultHeader),
-            0xdb => Ok(JpegMarker::DefineQuantizationTable),
-            0xc0 => Ok(JpegMarker::StartOfFrame),
-            0xc4 => Ok(JpegMarker::DefineHuffmanTable),
-            0xda => Ok(JpegMarker::StartOfScan),
-            0xd9 => Ok(JpegMarker::EndOfImage),
-            0xec => Ok(JpegMarker::PictInfo),
-            0xee => Ok(JpegMarker::AdobeApp14),
-            0xfe => Ok(JpegMarker::Comment),
-            0xe2 => Ok(JpegMarker::AppSeg2),
-            0xe1 => Ok(JpegMarker::AppSeg1),
-            _ => Err(InvalidJpegMarker { marker: value }),
-        }
-    }
-}
-
-fn print_8x8_matrix<T: Display + Copy>(x: &[T; 64]) {
-    for chunk in x.chunks_exact(8) {
-        print!("[");
-        for &x in chunk {
-            print!("{x: >4} ");
-        }
-        println!(" ]");
-    }
-}
-
-fn print_dst_quant_table(dst: u8) {
-    match dst {
-        0 => println!("Luminance"),
-        1 => println!("Chrominance"),
-        _ => unreachable!("invalid dst for quant matrix"),
-    }
-}
+use crate::ec::{HuffmanTree, DEFAULT_AC_TABLES, DEFAULT_DC_TABLES};
+use crate::error::{DecodeError, TableKind};
+use crate::image::{Image, PixelFormat};
+use crate::lossless::{read_difference, Predictor};
+use crate::progressive::{decode_ac_first, decode_ac_refine, decode_dc_first, decode_dc_refine};
+use crate::segment::{
+    parse_segment, read_length, Adobe, CodingProcess, EntropyCoding, Frame, FrameComponent,
+    JpegMarker, Scan, Segment, SegmentContents, TableClass,
+};
+use crate::upsample::{upsample_box, upsample_h2v1_fancy, upsample_h2v2_fancy, Upsampling};
 
 #[rustfmt::skip]
 static _ZIGZAG_ORDER: [u8; 64] = [
@@ -135,8 +53,8 @@ static ZIGZAG_DECODE_ORDER: [u8; 64] = [
 // to reduce cache eviction and memory consumption?
 
 #[inline(never)]
-pub fn zigzag_descan(coeffs: &[i16; 64]) -> [i16; 64] {
-    let mut new = [0; 64];
+pub fn zigzag_descan<T: Copy + Default>(coeffs: &[T; 64]) -> [T; 64] {
+    let mut new = [T::default(); 64];
 
     for i in 0..64 {
         new[i] = coeffs[ZIGZAG_DECODE_ORDER[i] as usize];
@@ -145,58 +63,76 @@ pub fn zigzag_descan(coeffs: &[i16; 64]) -> [i16; 64] {
     new
 }
 
-pub struct Decoder {
-    reader: BufReader<File>,
-    d: Dimensions,
-}
-
-struct Dimensions {
-    w: u16,
-    h: u16,
-}
-
-fn decode_mcu_block(
-    huff_trees: &[[HuffmanTree; 2]; 2],
-    quant_matrices: &[[u8; 64]; 2],
-    bitreader: &mut BitReader,
-    pred: &mut [i16; 3],
-) -> [[i16; 64]; 3] {
-    // 8x8 blocks stored in this order:
-    // Y, Cr, Cb
-
-    // huff tree:
-    // [component][is_dc]
-
-    // TODO do not assume 3 components
-    let y = decode_dct_matrix(&huff_trees[0], &quant_matrices[0], bitreader, &mut pred[0]);
-    let cr = decode_dct_matrix(&huff_trees[1], &quant_matrices[1], bitreader, &mut pred[1]);
-    let cb = decode_dct_matrix(&huff_trees[1], &quant_matrices[1], bitreader, &mut pred[2]);
-
-    [y, cr, cb]
+/// Baseline, extended, progressive and lossless JPEG decoder, for both
+/// huffman and arithmetic-coded images
+///
+/// Arithmetic-coded lossless (SOF11) images are not supported, and fail
+/// with [`DecodeError::Unsupported`] at their first scan.
+///
+/// Reads from any [`BufRead`] source. Wrap files and sockets in a
+/// [`std::io::BufReader`]; in-memory data can be passed directly as a
+/// `&[u8]`, which is read without any intermediate copies.
+///
+/// Motion-JPEG frames without huffman tables are decoded with the example
+/// tables of Annex K.
+pub struct Decoder<R> {
+    reader: CountingReader<R>,
+    /// Marker that was already consumed while skipping entropy-coded data
+    pending_marker: Option<u16>,
+    /// Quantization tables, in zigzag order
+    quant_matrices: [Option<[u16; 64]>; 4],
+    // up to 4 components
+    // index with
+    // [slot][is_dc]
+    huffman_table: [[HuffmanTree; 2]; 4],
+    /// Arithmetic coding conditioning of every table slot
+    conditioning: Conditioning,
+    frame: Option<Frame>,
+    /// Whether the file has a JFIF header, which implies YCbCr
+    jfif: bool,
+    /// Adobe APP14 header, which gives the color transform of the image
+    adobe: Option<Adobe>,
+    /// Color space set by the user, instead of the one inferred from the
+    /// file
+    color_space: Option<ColorSpace>,
+    /// Allocated at the first scan
+    components: Vec<Component>,
+    upsampling: Upsampling,
+    /// Requested output format, defaults to the native format of the image
+    output_format: Option<PixelFormat>,
+    /// Number of MCUs between restart markers, 0 if there are none
+    restart_interval: u16,
+    /// Largest number of bytes allocated for coefficients or samples
+    max_memory: u64,
 }
 
 // Call this function BEFORE doing zigzag descan
 #[inline(never)]
-fn dequantize(coeffs: &mut [i16; 64], quant_matrix: &[u8; 64]) {
+fn dequantize(coeffs: &[i16; 64], quant_matrix: &[u16; 64]) -> [i32; 64] {
+    let mut out = [0; 64];
+
+    // can't overflow, both factors fit in 16 bits
     for i in 0..64 {
-        coeffs[i] *= i16::from(quant_matrix[i]);
+        out[i] = i32::from(coeffs[i]) * i32::from(quant_matrix[i]);
     }
+
+    out
 }
 
-fn decode_dct_matrix(
-    huff_trees: &[HuffmanTree; 2],
-    quant_matrix: &[u8; 64],
-    bitreader: &mut BitReader,
+/// Decodes the quantized coefficients of a block, in zigzag order
+fn decode_dct_matrix<R: BufRead>(
+    dc_huff_tree: &HuffmanTree,
+    ac_huff_tree: &HuffmanTree,
+    bitreader: &mut BitReader<R>,
     dc_pred: &mut i16,
-) -> [i16; 64] {
-    let [ac_huff_tree, dc_huff_tree] = huff_trees;
+) -> Result<[i16; 64], DecodeError> {
+    let (dc_bits, dc_diff) = dc_huff_tree.read_value(bitreader)?;
 
-    let dc_bits = dc_huff_tree.read_code(bitreader).unwrap();
-
-    // get N bits
-    let dc_val = bitreader.get_n_bits(dc_bits as u32).unwrap();
+    if dc_bits > 15 {
+        return Err(DecodeError::CorruptData("DC difference out of range"));
+    }
 
-    let dc_coeff = *dc_pred + sign_code(dc_bits as u32, dc_val);
+    let dc_coeff = dc_pred.wrapping_add(dc_diff);
     *dc_pred = dc_coeff;
 
     // before de-zigzag
@@ -206,24 +142,23 @@ fn decode_dct_matrix(
     let mut idx = 1;
 
     loop {
-        let symbol = ac_huff_tree.read_code(bitreader).unwrap();
+        // the low nibble of the symbol is the number of bits of the value
+        let (symbol, ac_coeff) = ac_huff_tree.read_value(bitreader)?;
 
         // EOB reached
         if symbol == 0 {
             break;
         }
 
-        // how many bits to read
-        let ac_bits = symbol & 0xf;
-
         // how many preceeding zeros there are before this coefficient
         let run_length = symbol >> 4;
 
-        let ac_val = bitreader.get_n_bits(ac_bits as u32).unwrap();
-        let ac_coeff = sign_code(ac_bits as u32, ac_val);
-
         idx += run_length as usize;
 
+        if idx >= 64 {
+            return Err(DecodeError::CorruptData("AC run past end of block"));
+        }
+
         // TODO maybe do zigzag here?
         mcu_block[idx] = ac_coeff;
 
@@ -234,419 +169,1326 @@ fn decode_dct_matrix(
         }
     }
 
-    dequantize(&mut mcu_block, quant_matrix);
+    Ok(mcu_block)
+}
 
-    // undo zigzag scan order
-    zigzag_descan(&mcu_block)
+/// Entropy decoding state that is carried over from one MCU to the next,
+/// and reset at every restart marker
+struct ScanState {
+    /// DC prediction of every component in the scan
+    dc_pred: Vec<i16>,
+    /// Number of blocks left in an EOB run, progressive AC scans only
+    eob_run: u16,
+    /// MCU row where the current restart interval starts. Lossless scans
+    /// predict its first line without the line above.
+    restart_row: usize,
+    /// Decoder and statistics of arithmetic-coded frames
+    arithmetic: ArithmeticState,
 }
 
-#[allow(unused)]
-pub fn print_huffman_code(is_dc: bool, symbol: u8, code: u16, bits: usize) {
-    if is_dc {
-        println!("{symbol: >3}  :  {:0width$b}", code, width = bits);
-    } else {
-        // bit count is in the low 4 bits of the symbol
+impl ScanState {
+    fn new(num_components: usize, conditioning: Conditioning) -> Self {
+        Self {
+            dc_pred: vec![0; num_components],
+            eob_run: 0,
+            restart_row: 0,
+            arithmetic: ArithmeticState::new(num_components, conditioning),
+        }
+    }
 
-        let bit_count = symbol & 0xf;
+    fn reset(&mut self, mcu_row: usize) {
+        self.dc_pred.fill(0);
+        self.eob_run = 0;
+        self.restart_row = mcu_row;
+        self.arithmetic.reset();
+    }
+}
 
-        // how many preceeding zeros there are before this coefficient
-        let run_length = (symbol & 0xf0) >> 4;
-
-        println!(
-            "({}, {})    \t:  {:0width$b}",
-            bit_count,
-            run_length,
-            code,
-            width = bits
-        );
+/// Decodes the coefficients of a block that are coded in the scan. `i` is
+/// the index of the component in the scan.
+fn decode_block<R: BufRead>(
+    block: &mut [i16; 64],
+    (frame, scan): (&Frame, &Scan),
+    (i, dc, ac): (usize, &HuffmanTree, &HuffmanTree),
+    bitreader: &mut BitReader<R>,
+    state: &mut ScanState,
+) -> Result<(), DecodeError> {
+    if frame.entropy_coding == EntropyCoding::Arithmetic {
+        return decode_block_arithmetic(block, (frame, scan), i, bitreader, state);
+    }
+
+    let band = (scan.spectral_start, scan.spectral_end);
+    let approx_low = scan.approx_low;
+
+    let dc_pred = &mut state.dc_pred[i];
+    let eob_run = &mut state.eob_run;
+
+    if frame.process != CodingProcess::Progressive {
+        *block = decode_dct_matrix(dc, ac, bitreader, dc_pred)?;
+        return Ok(());
+    }
+
+    match (scan.spectral_start, scan.approx_high) {
+        (0, 0) => decode_dc_first(block, dc, bitreader, dc_pred, approx_low),
+        (0, _) => decode_dc_refine(block, bitreader, approx_low),
+        (_, 0) => decode_ac_first(block, ac, bitreader, band, approx_low, eob_run),
+        _ => decode_ac_refine(block, ac, bitreader, band, approx_low, eob_run),
+    }
+}
+
+/// Decodes the coefficients of a block of an arithmetic-coded frame
+fn decode_block_arithmetic<R: BufRead>(
+    block: &mut [i16; 64],
+    (frame, scan): (&Frame, &Scan),
+    i: usize,
+    bitreader: &mut BitReader<R>,
+    state: &mut ScanState,
+) -> Result<(), DecodeError> {
+    let band = (scan.spectral_start, scan.spectral_end);
+    let approx_low = scan.approx_low;
+
+    let tables = BlockTables {
+        component: i,
+        dc_table: scan.components[i].dc_table as usize,
+        ac_table: scan.components[i].ac_table as usize,
+    };
+
+    let st = &mut state.arithmetic;
+    let dc_pred = &mut state.dc_pred[i];
+
+    if frame.process != CodingProcess::Progressive {
+        // only the nonzero coefficients are decoded
+        *block = [0; 64];
+
+        arithmetic::decode_dc_first(block, st, bitreader, tables, dc_pred, 0)?;
+        return arithmetic::decode_ac_first(block, st, bitreader, tables, (1, 63), 0);
+    }
+
+    match (scan.spectral_start, scan.approx_high) {
+        (0, 0) => arithmetic::decode_dc_first(block, st, bitreader, tables, dc_pred, approx_low),
+        (0, _) => arithmetic::decode_dc_refine(block, st, bitreader, approx_low),
+        (_, 0) => arithmetic::decode_ac_first(block, st, bitreader, tables, band, approx_low),
+        _ => arithmetic::decode_ac_refine(block, st, bitreader, tables, band, approx_low),
     }
 }
 
-#[inline(always)]
-fn ycbcr_to_rgb(y: f32, cb: f32, cr: f32) -> [u8; 3] {
-    // let r = f32::mul_add(1.402, cr - 128.0, y);
-    // let g = f32::mul_add(-0.71414, cr - 128.0, f32::mul_add(-0.34414, cb - 128.0, y));
-    // let b = f32::mul_add(1.772, cb - 128.0, y);
+/// Decodes the blocks of a single MCU, for every component in the scan.
+/// `scan_components` holds the index of each component in the frame, and
+/// its DC and AC tables.
+fn decode_mcu<R: BufRead>(
+    components: &mut [Component],
+    scan_components: &[(usize, &HuffmanTree, &HuffmanTree)],
+    (frame, scan): (&Frame, &Scan),
+    (mcu_x, mcu_y): (usize, usize),
+    bitreader: &mut BitReader<R>,
+    state: &mut ScanState,
+) -> Result<(), DecodeError> {
+    let interleaved = scan_components.len() > 1;
+
+    let predictor = Predictor::new(scan.spectral_start, scan.approx_low, frame.precision);
+
+    // each component contributes h * v blocks to an MCU of an interleaved
+    // scan, in the order the components appear in the scan
+    for (i, &(index, dc, ac)) in scan_components.iter().enumerate() {
+        let component = &mut components[index];
+
+        let (h_blocks, v_blocks) = if interleaved {
+            (component.h, component.v)
+        } else {
+            (1, 1)
+        };
+
+        for v in 0..v_blocks {
+            for h in 0..h_blocks {
+                let x = mcu_x * h_blocks + h;
+                let y = mcu_y * v_blocks + v;
+
+                // in lossless frames, every data unit is a single sample
+                if frame.process == CodingProcess::Lossless {
+                    let stride = component.blocks_w;
+
+                    let first_line = y == state.restart_row * v_blocks;
+                    let prediction =
+                        predictor.predict(&component.samples, stride, (x, y), first_line);
+
+                    let difference = read_difference(dc, bitreader)?;
+                    component.samples[y * stride + x] =
+                        predictor.reconstruct(prediction, difference);
+
+                    continue;
+                }
 
-    let r = 1.402 * (cr - 128.0) + y;
-    let g = -0.71414 * (cr - 128.0) + -0.34414 * (cb - 128.0) + y;
-    let b = 1.772 * (cb - 128.0) + y;
+                decode_block(
+                    &mut component.coeffs[y * component.blocks_w + x],
+                    (frame, scan),
+                    (i, dc, ac),
+                    bitreader,
+                    state,
+                )?;
+            }
+        }
+    }
 
-    let r = r as u8;
-    let g = g as u8;
-    let b = b as u8;
+    Ok(())
+}
 
-    [r, g, b]
+/// Decoding state of a single component of the frame
+struct Component {
+    id: u8,
+    /// Quantization table selector
+    quant_table: usize,
+    /// Horizontal and vertical sampling factors
+    h: usize,
+    v: usize,
+    /// Size of the component in samples, without any padding
+    width: usize,
+    height: usize,
+    /// Size of the component in data units, padded to a whole number of
+    /// MCUs. Data units are 8x8 blocks, or single samples in lossless
+    /// frames.
+    blocks_w: usize,
+    blocks_h: usize,
+    /// Quantized DCT coefficients of every block in zigzag order,
+    /// stored row by row
+    coeffs: Vec<[i16; 64]>,
+    /// Samples of lossless frames, stored row by row
+    samples: Vec<u16>,
 }
 
-#[inline(never)]
-pub fn to_rgb((w, h): (u16, u16), buf: &mut [u8], blocks: &[[[i16; 64]; 3]]) {
-    let bh = (h / 8) as usize;
-    let bw = (w / 8) as usize;
+impl Component {
+    fn new(component: &FrameComponent, frame: &Frame) -> Result<Self, DecodeError> {
+        let h = component.h_sampling as usize;
+        let v = component.v_sampling as usize;
+
+        let (h_max, v_max) = max_sampling(frame);
+        let (mcus_x, mcus_y) = mcu_count(frame);
+
+        let blocks_w = mcus_x * h;
+        let blocks_h = mcus_y * v;
+
+        let mut component = Self {
+            id: component.id,
+            quant_table: component.quant_table as usize,
+            h,
+            v,
+            width: (frame.width as usize * h).div_ceil(h_max),
+            height: (frame.height as usize * v).div_ceil(v_max),
+            blocks_w,
+            blocks_h,
+            coeffs: Vec::new(),
+            samples: Vec::new(),
+        };
+
+        component.resize(frame)?;
+
+        Ok(component)
+    }
+
+    /// Adds rows of data units until there are at least `rows`, while the
+    /// first scan of a frame without a height in its header is decoded
+    fn grow(&mut self, rows: usize, frame: &Frame) -> Result<(), DecodeError> {
+        if rows > self.blocks_h {
+            self.blocks_h = rows;
+            self.resize(frame)?;
+        }
 
-    for y in 0..bh {
-        for x in 0..bw {
-            let block = blocks[y * bw + x];
+        Ok(())
+    }
 
-            let mut coeffs = [[0.0; 64]; 3];
+    /// Crops or pads the data units to the height of the frame, once it
+    /// is defined by the DNL segment
+    fn set_height(&mut self, frame: &Frame) -> Result<(), DecodeError> {
+        let (_, v_max) = max_sampling(frame);
+        let (_, mcus_y) = mcu_count(frame);
 
-            let mut out = [[0.0; 64]; 3];
+        self.height = (frame.height as usize * self.v).div_ceil(v_max);
+        self.blocks_h = mcus_y * self.v;
+        self.resize(frame)
+    }
 
-            // cast dct coefficients to f64
-            for p in 0..3 {
-                for i in 0..64 {
-                    coeffs[p][i] = block[p][i] as f32;
-                }
-            }
+    /// Allocates `blocks_w` by `blocks_h` data units
+    fn resize(&mut self, frame: &Frame) -> Result<(), DecodeError> {
+        let len = self.blocks_w * self.blocks_h;
+
+        match frame.process {
+            CodingProcess::Lossless => try_resize(&mut self.samples, len, 0),
+            _ => try_resize(&mut self.coeffs, len, [0; 64]),
+        }
+    }
 
-            for p in 0..3 {
-                idct(&coeffs[p], &mut out[p]);
+    /// Dequantizes every block and transforms it back to samples of
+    /// `precision` bits. Returns a plane of `8 * blocks_w` by `8 * blocks_h`
+    /// samples.
+    fn to_samples(&self, quant_matrix: &[u16; 64], precision: u8) -> Vec<u16> {
+        // level shift
+        let center = (1 << (precision - 1)) as f32;
+        let max = ((1 << precision) - 1) as f32;
+
+        let stride = 8 * self.blocks_w;
+
+        let mut plane = vec![0; stride * 8 * self.blocks_h];
+
+        for (i, block) in self.coeffs.iter().enumerate() {
+            let (bx, by) = (i % self.blocks_w, i / self.blocks_w);
+
+            let block = dequantize(block, quant_matrix);
+
+            // undo zigzag scan order
+            let block = zigzag_descan(&block);
+
+            let mut coeffs = [0.0; 64];
+            let mut out = [0.0; 64];
+
+            // cast dct coefficients to f32
+            for i in 0..64 {
+                coeffs[i] = block[i] as f32;
             }
 
-            for y2 in 0..8 {
-                for x2 in 0..8 {
-                    let yp = out[0][y2 * 8 + x2] + 128.0;
-                    let cb = out[1][y2 * 8 + x2] + 128.0;
-                    let cr = out[2][y2 * 8 + x2] + 128.0;
+            idct(&coeffs, &mut out);
 
-                    let px = ycbcr_to_rgb(yp, cb, cr);
+            for (y, row) in out.chunks_exact(8).enumerate() {
+                let dst = &mut plane[(8 * by + y) * stride + 8 * bx..][..8];
 
-                    buf[3 * (y * bw * 8 * 8 + 8 * x + y2 * w as usize + x2)..][..3]
-                        .copy_from_slice(&px)
+                for (sample, &x) in dst.iter_mut().zip(row) {
+                    *sample = (x + center).round().clamp(0.0, max) as u16;
                 }
             }
         }
+
+        plane
     }
 }
 
-impl Decoder {
-    pub fn new(file: File) -> Self {
-        Decoder {
-            reader: BufReader::new(file),
-            d: Dimensions { w: 0, h: 0 },
+/// Resizes `vec` to `len` elements, returning an error instead of aborting
+/// if the memory can't be allocated
+fn try_resize<T: Clone>(vec: &mut Vec<T>, len: usize, value: T) -> Result<(), DecodeError> {
+    let additional = len.saturating_sub(vec.len());
+
+    // rows are added one at a time while decoding a frame with a DNL
+    // segment, which needs the usual amortized growth
+    let reserved = match vec.is_empty() {
+        true => vec.try_reserve_exact(additional),
+        false => vec.try_reserve(additional),
+    };
+
+    reserved.map_err(|_| DecodeError::OutOfMemory)?;
+    vec.resize(len, value);
+
+    Ok(())
+}
+
+/// Checks that the parameters of a progressive scan are consistent
+fn check_progressive_scan(scan: &Scan) -> Result<(), DecodeError> {
+    let (start, end) = (scan.spectral_start, scan.spectral_end);
+
+    if start > end || end > 63 {
+        return Err(DecodeError::InvalidSegment("invalid spectral selection"));
+    }
+
+    // DC and AC coefficients are never coded in the same scan
+    if start == 0 && end != 0 {
+        return Err(DecodeError::InvalidSegment(
+            "progressive scan mixes DC and AC coefficients",
+        ));
+    }
+
+    if start > 0 && scan.components.len() != 1 {
+        return Err(DecodeError::InvalidSegment(
+            "progressive AC scan with more than one component",
+        ));
+    }
+
+    if scan.approx_low > 13 || (scan.approx_high != 0 && scan.approx_high != scan.approx_low + 1) {
+        return Err(DecodeError::InvalidSegment(
+            "invalid successive approximation",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the parameters of a lossless scan
+fn check_lossless_scan(scan: &Scan, precision: u8) -> Result<(), DecodeError> {
+    // predictor 0 (no prediction) is only used in hierarchical mode
+    if !(1..=7).contains(&scan.spectral_start) {
+        return Err(DecodeError::InvalidSegment("invalid lossless predictor"));
+    }
+
+    if scan.approx_low >= precision {
+        return Err(DecodeError::InvalidSegment(
+            "point transform larger than the sample precision",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that the symbols of a huffman table code values that fit in the
+/// coefficients or differences of the frame (F.1.2.1.2, F.1.2.2.1, H.1.2.2)
+fn check_huffman_symbols(
+    table: &HuffmanTree,
+    (kind, index): (TableKind, u8),
+    frame: &Frame,
+) -> Result<(), DecodeError> {
+    let lossless = frame.process == CodingProcess::Lossless;
+    let progressive = frame.process == CodingProcess::Progressive;
+
+    let valid = |&symbol: &u8| {
+        let (run, size) = (symbol >> 4, symbol & 0xf);
+
+        match kind {
+            TableKind::DcHuffman if lossless => symbol <= 16,
+            // DC differences have up to 3 more bits than the samples
+            TableKind::DcHuffman => symbol <= frame.precision + 3,
+            // sequential scans only have EOB and ZRL codes without a
+            // value, progressive scans also have EOB runs
+            _ if size == 0 => progressive || run == 0 || run == 15,
+            _ => size <= frame.precision + 2,
         }
+    };
+
+    match table.symbols.iter().all(valid) {
+        true => Ok(()),
+        false => Err(DecodeError::InvalidHuffmanTable {
+            kind,
+            index,
+            reason: "symbol out of range for the frame",
+        }),
     }
+}
 
-    pub fn decode(&mut self) -> Result<(), DecodeError> {
-        let mut quant_matrices = [[0u8; 64]; 2];
-        let mut quant_mapping = Vec::new();
+/// Number of MCUs in each direction, the image is padded to a whole
+/// number of MCUs
+fn mcu_count(frame: &Frame) -> (usize, usize) {
+    let (h_max, v_max) = max_sampling(frame);
+    let unit = data_unit_size(frame);
 
-        let mut out_file = File::create("out.ppm").unwrap();
+    let mcus_x = (frame.width as usize).div_ceil(unit * h_max);
+    let mcus_y = (frame.height as usize).div_ceil(unit * v_max);
 
-        // Should not exist, only decoded buffer
-        let mut blocks = Vec::new();
+    (mcus_x, mcus_y)
+}
 
-        // up to 4 components
-        // index with
-        // [component][is_dc]
-        let mut huffman_table: [[HuffmanTree; 2]; 2] = [
-            [HuffmanTree::new(), HuffmanTree::new()],
-            [HuffmanTree::new(), HuffmanTree::new()],
-        ];
+/// Bytes needed to decode `mcus_y` rows of MCUs to `format`: the
+/// coefficients, or the samples of lossless frames, of every component,
+/// and the planes and pixel buffers the image is reconstructed in
+fn buffer_size(
+    frame: &Frame,
+    mcus_y: usize,
+    (color_space, format): (ColorSpace, PixelFormat),
+) -> u64 {
+    let (mcus_x, _) = mcu_count(frame);
+    let (h_max, v_max) = max_sampling(frame);
+    let unit = data_unit_size(frame);
+
+    let unit_size = match frame.process {
+        CodingProcess::Lossless => size_of::<u16>(),
+        _ => size_of::<[i16; 64]>(),
+    };
+
+    let units = frame
+        .components
+        .iter()
+        .map(|c| c.h_sampling as u64 * c.v_sampling as u64)
+        .sum::<u64>();
+
+    let coefficients = units * mcus_x as u64 * mcus_y as u64 * unit_size as u64;
+
+    // every plane is upsampled to the padded size of the image
+    let pixels = (unit * h_max * mcus_x) as u64 * (unit * v_max * mcus_y) as u64;
+
+    let planes = match (format.components(), color_space) {
+        (1, ColorSpace::YCbCr) => 1,
+        _ => color_space.components(),
+    };
+
+    // interleaved samples converted between color spaces
+    let temporary = match (format.components(), color_space) {
+        (1, ColorSpace::Rgb) => 3,
+        (3, ColorSpace::Cmyk | ColorSpace::Ycck) => 4,
+        (1, ColorSpace::Cmyk | ColorSpace::Ycck) => 4 + 3,
+        _ => 0,
+    };
+
+    // the planes, the samples of the output, the temporary samples and
+    // the output itself are all alive at the end of the reconstruction
+    let samples = planes + format.components() + temporary;
+    let per_pixel = samples * size_of::<u16>() + format.bytes_per_pixel();
+
+    coefficients + pixels * per_pixel as u64
+}
 
-        loop {
-            // Very tiny optimization idea: avoid swapping bytes when
-            // reading the marker by just comparing the bytes already
-            // swapped (on little endian). On big endian, compare the
-            // bytes as normal. No swapping required either way.
-            let marker = if let Ok(marker) = read_u16(&mut self.reader) {
-                marker
-            } else {
-                break;
-            };
+/// Checks the memory needed for `mcus_y` rows of MCUs against `limit`
+fn check_memory(
+    frame: &Frame,
+    mcus_y: usize,
+    output: (ColorSpace, PixelFormat),
+    limit: u64,
+) -> Result<(), DecodeError> {
+    let required = buffer_size(frame, mcus_y, output);
+
+    match required > limit {
+        true => Err(DecodeError::LimitExceeded { required, limit }),
+        false => Ok(()),
+    }
+}
 
-            let marker = JpegMarker::try_from(marker).unwrap();
+/// Width and height of a data unit in samples
+fn data_unit_size(frame: &Frame) -> usize {
+    match frame.process {
+        CodingProcess::Lossless => 1,
+        _ => 8,
+    }
+}
 
-            println!("{}", marker.segment_name());
+/// Largest horizontal and vertical sampling factors of the frame, which
+/// give the size of an MCU in blocks
+fn max_sampling(frame: &Frame) -> (usize, usize) {
+    frame.components.iter().fold((1, 1), |(h, v), c| {
+        (h.max(c.h_sampling as usize), v.max(c.v_sampling as usize))
+    })
+}
 
-            match marker {
-                JpegMarker::StartOfImage => {}
-                JpegMarker::EndOfImage => {}
-                // Start of scan (actual entropy coded image data)
-                JpegMarker::StartOfScan => {
-                    // What the hell is this length for?
-                    let len = read_u16(&mut self.reader)?;
+impl<R: BufRead> Decoder<R> {
+    pub fn new(reader: R) -> Self {
+        Decoder {
+            reader: CountingReader::new(reader),
+            pending_marker: None,
+            quant_matrices: [None; 4],
+            huffman_table: std::array::from_fn(|_| [HuffmanTree::new(), HuffmanTree::new()]),
+            conditioning: Conditioning::default(),
+            frame: None,
+            jfif: false,
+            adobe: None,
+            color_space: None,
+            components: Vec::new(),
+            upsampling: Upsampling::default(),
+            output_format: None,
+            restart_interval: 0,
+            max_memory: 1 << 30,
+        }
+    }
 
-                    self.reader.seek_relative((len - 2) as i64)?;
+    /// Sets the pixel format of the decoded image. By default, grayscale
+    /// images are decoded to [`PixelFormat::Gray8`], color images to
+    /// [`PixelFormat::Rgb8`] and CMYK or YCCK images to
+    /// [`PixelFormat::Cmyk8`], or to the 16-bit formats for images with
+    /// more than 8 bits per sample. Grayscale images can be expanded to
+    /// RGB, and only the luma of color images is decoded for grayscale
+    /// output. CMYK images can be converted to RGB or grayscale, but
+    /// only CMYK images can be decoded to CMYK. 8-bit output of a 12-bit
+    /// image keeps the top 8 bits.
+    pub fn set_output_format(&mut self, format: PixelFormat) {
+        self.output_format = Some(format);
+    }
 
-                    blocks.reserve((self.d.h / 8) as usize * (self.d.w / 8) as usize);
+    /// Sets the method used to upsample subsampled chroma components.
+    /// Defaults to [`Upsampling::Fancy`].
+    pub fn set_upsampling(&mut self, upsampling: Upsampling) {
+        self.upsampling = upsampling;
+    }
 
-                    let mut bitreader = BitReader::new(&mut self.reader);
+    /// Overrides the color space of the image, for files where it can't be
+    /// inferred correctly. It must have as many components as the image.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) {
+        self.color_space = Some(color_space);
+    }
 
-                    let mut dc_pred = [0; 3];
+    /// Sets the largest number of bytes that may be allocated to decode the
+    /// image: its coefficients, or its samples for lossless images, and
+    /// the buffers the image is reconstructed in.
+    /// Larger images fail with [`DecodeError::LimitExceeded`] before their
+    /// first scan is decoded, so [`Decoder::read_info`] still returns their
+    /// size. Defaults to 1 GiB.
+    pub fn set_max_memory(&mut self, bytes: u64) {
+        self.max_memory = bytes;
+    }
 
-                    for _y in 0..self.d.h / 8 {
-                        for _x in 0..self.d.w / 8 {
-                            let mcu_block = decode_mcu_block(
-                                &huffman_table,
-                                &quant_matrices,
-                                &mut bitreader,
-                                &mut dc_pred,
-                            );
+    /// Returns the color space of the image once the frame header is read,
+    /// either the one set with [`Decoder::set_color_space`], or the one
+    /// inferred from the file.
+    ///
+    /// Like libjpeg, a JFIF header implies YCbCr, then the transform flag
+    /// of an Adobe header decides, and otherwise the component IDs: three
+    /// components with the IDs 'R', 'G' and 'B' are RGB. Four components
+    /// are CMYK, unless an Adobe header has a transform other than 0, which
+    /// means YCCK. Returns `None` for other numbers of components, which
+    /// can't be decoded.
+    pub fn color_space(&self) -> Option<ColorSpace> {
+        let frame = self.frame.as_ref()?;
+
+        if let Some(color_space) = self.color_space {
+            return Some(color_space);
+        }
 
-                            blocks.push(mcu_block);
-                        }
+        let transform = self.adobe.as_ref().map(|adobe| adobe.transform);
+
+        let color_space = match frame.components.len() {
+            1 => ColorSpace::Gray,
+            3 if self.jfif => ColorSpace::YCbCr,
+            3 => match transform {
+                Some(0) => ColorSpace::Rgb,
+                Some(_) => ColorSpace::YCbCr,
+                None => {
+                    let ids = frame.components.iter().map(|c| c.id);
+
+                    match ids.eq(*b"RGB") {
+                        true => ColorSpace::Rgb,
+                        false => ColorSpace::YCbCr,
                     }
                 }
-                JpegMarker::ApplicationDefaultHeader => {
-                    let len = read_u16(&mut self.reader)?;
+            },
+            4 => match transform {
+                Some(0) | None => ColorSpace::Cmyk,
+                Some(_) => ColorSpace::Ycck,
+            },
+            _ => return None,
+        };
+
+        Some(color_space)
+    }
+
+    /// Reads the next marker and its segment. The reader is left at the
+    /// start of the entropy-coded data after a SOS segment.
+    ///
+    /// Returns `None` at the end of the data.
+    fn read_segment(&mut self) -> Result<Option<Segment>, DecodeError> {
+        let (marker, offset) = match self.pending_marker.take() {
+            // both bytes of the marker were already consumed
+            Some(marker) => (marker, self.reader.position() - 2),
+            None => {
+                let offset = self.reader.position();
+
+                // Very tiny optimization idea: avoid swapping bytes when
+                // reading the marker by just comparing the bytes already
+                // swapped (on little endian). On big endian, compare the
+                // bytes as normal. No swapping required either way.
+                let marker = match read_u16(&mut self.reader) {
+                    Ok(0xffff) => 0xff00 | read_marker_byte(&mut self.reader)? as u16,
+                    Ok(marker) => marker,
+                    // tolerate files that are missing the EOI marker
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+
+                (marker, offset)
+            }
+        };
+
+        let marker = JpegMarker::try_from(marker)
+            .map_err(|marker| DecodeError::UnknownMarker { marker, offset })?;
+
+        let (length, contents) = if marker.has_segment() {
+            let len = read_length(&mut self.reader)?;
+
+            (
+                len as u16 + 2,
+                parse_segment(&mut self.reader, marker, len)?,
+            )
+        } else {
+            (0, SegmentContents::Empty)
+        };
+
+        Ok(Some(Segment {
+            marker,
+            offset,
+            length,
+            contents,
+        }))
+    }
 
-                    let mut null_str = Vec::new();
+    /// Reads every marker segment in the file without decoding the image,
+    /// skipping over the entropy-coded data of each scan.
+    ///
+    /// This consumes the data, so the same decoder cannot be used to
+    /// decode the image afterwards.
+    pub fn inspect(&mut self) -> Result<Vec<Segment>, DecodeError> {
+        let mut segments = Vec::new();
 
-                    // TODO read len-2 bytes upfront, and search that area instead
-                    // of doing it this pretty garbage way
+        while let Some(segment) = self.read_segment()? {
+            let marker = segment.marker;
 
-                    // read null-terminated string
-                    let n_read = self.reader.read_until(0, &mut null_str)?;
-                    assert!(
-                        n_read <= len as usize - size_of::<u16>(),
-                        "Invalid length after marker in Application Default Header"
-                    );
+            segments.push(segment);
 
-                    let v_maj = read_u8(&mut self.reader)?;
-                    let v_min = read_u8(&mut self.reader)?;
+            match marker {
+                JpegMarker::StartOfScan => {
+                    let mut bitreader = BitReader::new(&mut self.reader);
 
-                    let units = read_u8(&mut self.reader)?;
+                    // restart markers are part of the entropy-coded data
+                    let marker = loop {
+                        match bitreader.next_marker()? {
+                            0xd0..=0xd7 => bitreader.clear_marker(),
+                            marker => break marker,
+                        }
+                    };
 
-                    let dx = read_u16(&mut self.reader)?;
-                    let dy = read_u16(&mut self.reader)?;
+                    self.pending_marker = Some(u16::from_be_bytes([0xff, marker]));
+                }
+                JpegMarker::EndOfImage => break,
+                _ => {}
+            }
+        }
 
-                    // Thumbnail information
-                    let tx = read_u8(&mut self.reader)?;
-                    let ty = read_u8(&mut self.reader)?;
+        Ok(segments)
+    }
 
-                    let s = std::str::from_utf8(&null_str[..null_str.len() - 1])
-                        .expect("Invalid UTF-8 in Application Default Header identifier");
+    /// Installs the luminance and chrominance tables of Annex K in slots 0
+    /// and 1, for Motion-JPEG frames that don't define any tables
+    fn set_default_huffman_tables(&mut self) {
+        for (slot, (dc, ac)) in DEFAULT_DC_TABLES.iter().zip(&DEFAULT_AC_TABLES).enumerate() {
+            self.huffman_table[slot] = [
+                HuffmanTree::from_table(&ac.0, ac.1),
+                HuffmanTree::from_table(&dc.0, dc.1),
+            ];
+        }
+    }
 
-                    println!();
-                    println!("Identifier:   {s}");
-                    println!("Version:      {v_maj}.{v_min}");
-                    println!("Units:        {units} (dpi)");
-                    println!("Density:      {dx}x{dy}");
-                    println!("Thumbnail:    {tx}x{ty}\n");
+    /// Applies a table or frame segment to the decoder state
+    fn apply_segment(&mut self, contents: SegmentContents) -> Result<(), DecodeError> {
+        match contents {
+            SegmentContents::QuantizationTables(tables) => {
+                for table in tables {
+                    self.quant_matrices[table.index as usize] = Some(table.values);
+                }
+            }
+            SegmentContents::HuffmanTables(tables) => {
+                // a table can be redefined between scans, later scans use
+                // the new definition
+                for table in tables {
+                    let is_dc = table.class == TableClass::Dc;
+
+                    // so AC is actually stored at index 0,
+                    // DC tree at index 1
+                    self.huffman_table[table.index as usize][is_dc as usize] =
+                        HuffmanTree::from_table(&table.counts, &table.symbols);
+                }
+            }
+            SegmentContents::ArithmeticConditioning(conditioning) => {
+                for c in &conditioning {
+                    self.conditioning.set(c);
                 }
-                JpegMarker::DefineQuantizationTable => {
-                    let mut len = read_u16(&mut self.reader)? as usize - 2;
-                    // one DQT can actually define multiple quant tables
-                    // porsche.jpg is an example of this, it defines 2 quantization
-                    // tables with one DQT marker
+            }
+            SegmentContents::RestartInterval(interval) => self.restart_interval = interval,
+            SegmentContents::NumberOfLines(lines) => {
+                let frame = self.frame.as_ref().ok_or(DecodeError::InvalidSegment(
+                    "DNL segment before frame header",
+                ))?;
+
+                // like libjpeg, ignore DNL segments of frames that already
+                // have a height
+                if frame.height == 0 {
+                    if lines == 0 {
+                        return Err(DecodeError::InvalidSegment("DNL segment with 0 lines"));
+                    }
 
-                    'dqt: loop {
-                        let qt_info = read_u8(&mut self.reader)?;
-                        len -= 1;
+                    let mut frame = frame.clone();
+                    frame.height = lines;
 
-                        // bottom 4 bits are the actual dst
-                        let dst = qt_info & 0xf;
+                    check_memory(&frame, mcu_count(&frame).1, self.output()?, self.max_memory)?;
 
-                        // index 0 or 1 only are allowed
-                        assert!(dst <= 1);
+                    for component in &mut self.components {
+                        component.set_height(&frame)?;
+                    }
 
-                        // if upper 4 bits are 0, 8-bit
-                        // otherwise 16-bit
-                        let qt_is_8_bit = (qt_info & 0xf0) == 0;
+                    self.frame = Some(frame);
+                }
+            }
+            SegmentContents::Jfif(_) => self.jfif = true,
+            SegmentContents::Adobe(adobe) => self.adobe = Some(adobe),
+            SegmentContents::Frame(frame) => {
+                if self.frame.is_some() {
+                    return Err(DecodeError::InvalidSegment("more than one frame header"));
+                }
 
-                        // for now we assume 8-bit, since 16-bit requires
-                        // reading twice as many bytes (roughly).
-                        assert!(qt_is_8_bit);
+                self.frame = Some(frame);
+            }
+            _ => {}
+        }
 
-                        // TODO this isn't correct for 16-bit
-                        self.reader.read_exact(&mut quant_matrices[dst as usize])?;
+        Ok(())
+    }
 
-                        len -= 64;
+    /// Reads the image dimensions, precision and components from the frame
+    /// header, without decoding the image data.
+    ///
+    /// Only the segments up to and including the frame header are read, so
+    /// this is cheap even for very large files. [`Decoder::decode`] can
+    /// still be called afterwards to decode the rest of the image.
+    pub fn read_info(&mut self) -> Result<Frame, DecodeError> {
+        loop {
+            if let Some(frame) = &self.frame {
+                return Ok(frame.clone());
+            }
 
-                        println!("Quant Matrix: {}-bit", if qt_is_8_bit { "8" } else { "16" });
-                        print_dst_quant_table(dst);
-                        print_8x8_matrix(&quant_matrices[dst as usize]);
-                        println!();
+            let segment = self.read_segment()?.ok_or(DecodeError::Truncated)?;
 
-                        if len == 0 {
-                            break 'dqt;
-                        }
-                    }
+            match segment.marker {
+                JpegMarker::StartOfScan => {
+                    return Err(DecodeError::InvalidSegment("scan before frame header"))
                 }
-                JpegMarker::DefineHuffmanTable => {
-                    // Up to 4 huffman tables are allowed in JPEG
+                JpegMarker::EndOfImage => return Err(DecodeError::Truncated),
+                _ => self.apply_segment(segment.contents)?,
+            }
+        }
+    }
 
-                    // Not actually needed, but we do have to advance forward 2 bytes.
-                    let mut len = read_u16(&mut self.reader)? - 2;
+    fn decode_scan(&mut self, scan: &Scan) -> Result<(), DecodeError> {
+        let frame = self
+            .frame
+            .as_ref()
+            .ok_or(DecodeError::InvalidSegment("scan before frame header"))?;
+
+        match frame.process {
+            CodingProcess::Lossless if !(2..=16).contains(&frame.precision) => {
+                return Err(DecodeError::InvalidSegment(
+                    "lossless sample precision must be 2 to 16 bits",
+                ));
+            }
+            CodingProcess::Lossless => {}
+            _ if frame.precision != 8 && frame.precision != 12 => {
+                return Err(DecodeError::Unsupported(
+                    "sample precision other than 8 or 12 bits",
+                ));
+            }
+            _ => {}
+        }
 
-                    'dht: loop {
-                        let ht_info = read_u8(&mut self.reader)?;
-                        len -= 1;
+        if frame.process == CodingProcess::Lossless
+            && frame.entropy_coding == EntropyCoding::Arithmetic
+        {
+            return Err(DecodeError::Unsupported("arithmetic-coded lossless frames"));
+        }
 
-                        let ht_num = ht_info & 0xf;
-                        assert!(ht_num <= 1);
+        // the height is defined by a DNL segment after the first scan, which
+        // is decoded until its data ends
+        let dnl = frame.height == 0;
 
-                        // bit index 4 (5th bit) specifies whether table is for AC/DC
-                        // 0 = DC, 1 = AC
-                        let is_dc = (ht_info >> 4) & 1 == 0;
+        if dnl && !self.components.is_empty() {
+            return Err(DecodeError::InvalidSegment(
+                "no DNL segment after the first scan",
+            ));
+        }
 
-                        // TODO maybe make a build flag for extra checks or something
-                        // ensure bit index 5-7 is 0
-                        assert!(ht_info & 0b1110_0000 == 0);
+        // the arithmetic decoder reads ahead, so the end of its data can't
+        // be found between two MCUs
+        if dnl && frame.entropy_coding == EntropyCoding::Arithmetic {
+            return Err(DecodeError::Unsupported(
+                "DNL segment in arithmetic-coded frames",
+            ));
+        }
 
-                        // I think component 0 is luma
-                        // and component 1 is chroma
+        if ![1, 3, 4].contains(&frame.components.len()) {
+            return Err(DecodeError::Unsupported(
+                "images without 1, 3 or 4 components",
+            ));
+        }
 
-                        println!(
-                            "Component {ht_num}, {} huffman tree",
-                            if is_dc { "DC" } else { "AC" }
-                        );
+        // Ss, Se, Ah and Al have fixed values in sequential scans, but
+        // like libjpeg, we don't care if the encoder got them wrong
+        match frame.process {
+            CodingProcess::Progressive => check_progressive_scan(scan)?,
+            CodingProcess::Lossless => check_lossless_scan(scan, frame.precision)?,
+            _ => {}
+        }
 
-                        // read 16 bytes for child node counts for 16 levels of huffman tree
-                        let mut buf = [0; 16];
+        let (h_max, v_max) = max_sampling(frame);
 
-                        self.reader.read_exact(&mut buf)?;
-                        len -= 16;
+        for component in &frame.components {
+            let (h, v) = (component.h_sampling as usize, component.v_sampling as usize);
 
-                        let mut code = 0u16;
-                        let mut bits = 0;
+            if h_max % h != 0 || v_max % v != 0 {
+                return Err(DecodeError::Unsupported(
+                    "non-integer chroma subsampling ratio",
+                ));
+            }
+        }
 
-                        let mut ht = HuffmanTree::new();
+        let output = self.output()?;
 
-                        let n_symbs = buf.iter().copied().map(|x| x as u16).sum::<u16>();
+        if self.components.is_empty() {
+            check_memory(frame, mcu_count(frame).1, output, self.max_memory)?;
 
-                        assert!(n_symbs > 0 && n_symbs <= 256);
+            self.components = frame
+                .components
+                .iter()
+                .map(|c| Component::new(c, frame))
+                .collect::<Result<_, _>>()?;
+        }
 
-                        let mut symbols = [0; 256];
-                        self.reader.read_exact(&mut symbols[..n_symbs as usize])?;
-                        len -= n_symbs;
+        // frame component index and huffman tables of every scan component
+        let mut scan_components: Vec<(usize, &HuffmanTree, &HuffmanTree)> =
+            Vec::with_capacity(scan.components.len());
+
+        for sc in &scan.components {
+            let index = self
+                .components
+                .iter()
+                .position(|c| c.id == sc.id)
+                .ok_or(DecodeError::InvalidSegment("scan component not in frame"))?;
+
+            if scan_components.iter().any(|&(i, ..)| i == index) {
+                return Err(DecodeError::InvalidSegment(
+                    "component appears twice in scan",
+                ));
+            }
 
-                        let mut idx = 0;
+            // AC tree is stored at index 0, DC tree at index 1
+            let dc = &self.huffman_table[sc.dc_table as usize][1];
+            let ac = &self.huffman_table[sc.ac_table as usize][0];
+
+            // progressive scans only use the tables of the coefficients
+            // they code, DC refinement doesn't use any. Lossless scans code
+            // the differences with the DC tables. Arithmetic coding has no
+            // tables, only optional conditioning.
+            let (needs_dc, needs_ac) = match frame.process {
+                _ if frame.entropy_coding == EntropyCoding::Arithmetic => (false, false),
+                CodingProcess::Progressive => (
+                    scan.spectral_start == 0 && scan.approx_high == 0,
+                    scan.spectral_start > 0,
+                ),
+                CodingProcess::Lossless => (true, false),
+                _ => (true, true),
+            };
 
-                        let mut last_len = buf.iter().position(|depth| *depth > 0).unwrap() + 1;
+            if needs_dc {
+                let kind = TableKind::DcHuffman;
 
-                        ht.l0 = last_len as u8;
+                if !dc.is_defined() {
+                    return Err(DecodeError::MissingTable {
+                        kind,
+                        index: sc.dc_table,
+                    });
+                }
 
-                        let mut cht = Vec::new();
+                check_huffman_symbols(dc, (kind, sc.dc_table), frame)?;
+            }
 
-                        for tdepth in buf {
-                            code <<= 1;
-                            bits += 1;
+            if needs_ac {
+                let kind = TableKind::AcHuffman;
 
-                            for _ in 0..tdepth {
-                                // let symbol = symbols[idx];
+                if !ac.is_defined() {
+                    return Err(DecodeError::MissingTable {
+                        kind,
+                        index: sc.ac_table,
+                    });
+                }
 
-                                // print_huffman_code(is_dc, symbol, code, bits);
+                check_huffman_symbols(ac, (kind, sc.ac_table), frame)?;
+            }
+
+            scan_components.push((index, dc, ac));
+        }
 
-                                if bits > last_len {
-                                    cht.push((code << (16 - bits), bits as u8, idx as u8));
-                                    last_len = bits;
-                                }
+        // an MCU of an interleaved scan has at most 10 data units
+        let mcu_units = scan_components
+            .iter()
+            .map(|&(index, ..)| self.components[index].h * self.components[index].v)
+            .sum::<usize>();
 
-                                idx += 1;
-                                code += 1;
-                            }
-                        }
+        if scan_components.len() > 1 && mcu_units > 10 {
+            return Err(DecodeError::InvalidSegment(
+                "interleaved scan with more than 10 blocks per MCU",
+            ));
+        }
+
+        // A scan with a single component is not interleaved: every MCU
+        // is one data unit, and only the data units that contain part of
+        // the image are coded, regardless of the sampling factors.
+        let (mcus_x, mcus_y) = match scan_components[..] {
+            [(index, ..)] => {
+                let component = &self.components[index];
+                let unit = data_unit_size(frame);
+
+                (
+                    component.width.div_ceil(unit),
+                    component.height.div_ceil(unit),
+                )
+            }
+            _ => mcu_count(frame),
+        };
 
-                        // for (x, y, z) in &cht {
-                        //     println!("[0x{:x}, {}, {}]", x, y, z);
-                        // }
+        let mcus = match dnl {
+            true => usize::MAX,
+            false => mcus_x * mcus_y,
+        };
 
-                        ht.cht = cht.into_boxed_slice();
+        let interleaved = scan_components.len() > 1;
 
-                        // TODO find better way to do this
-                        ht.symbols = symbols[..n_symbs as usize].to_vec().into_boxed_slice();
+        // frames are at most 65535 lines high
+        let max_mcus_y = 0xffff_usize.div_ceil(data_unit_size(frame) * max_sampling(frame).1);
+        let restart_interval = self.restart_interval as usize;
 
-                        // so AC is actually stored at index 0,
-                        // DC tree at index 1
-                        huffman_table[ht_num as usize][is_dc as usize] = ht;
+        let mut bitreader = BitReader::new(&mut self.reader);
 
-                        if len == 0 {
-                            break 'dht;
-                        }
+        let mut state = ScanState::new(scan_components.len(), self.conditioning);
+
+        // number of the next expected restart marker, modulo 8
+        let mut next_restart = 0;
+
+        let mut mcu = 0;
+
+        while mcu < mcus {
+            if dnl {
+                let mcu_y = mcu / mcus_x;
+
+                if mcu % mcus_x == 0 {
+                    if bitreader.at_end() {
+                        break;
                     }
 
-                    // TODO for check_decoder, ensure symbols read equals
-                    // sum of symbols read, and complies with the length
+                    // rows of MCUs of the frame the scan has reached
+                    let mcus_y = match scan_components[..] {
+                        [(index, ..)] => (mcu_y + 1).div_ceil(self.components[index].v),
+                        _ => mcu_y + 1,
+                    };
+
+                    check_memory(frame, mcus_y, output, self.max_memory)?;
                 }
-                // Other currently unsupported marker
-                JpegMarker::StartOfFrame => {
-                    let _len = read_u16(&mut self.reader)?;
 
-                    // bits per sample
-                    let data_precision = read_u8(&mut self.reader)?;
+                for &(index, ..) in &scan_components {
+                    let component = &mut self.components[index];
+                    let v = if interleaved { component.v } else { 1 };
+
+                    let rows = (mcu_y + 1) * v;
+
+                    if rows > max_mcus_y * component.v {
+                        return Err(DecodeError::CorruptData(
+                            "scan is longer than the largest frame height",
+                        ));
+                    }
 
-                    let height = read_u16(&mut self.reader)?;
-                    let width = read_u16(&mut self.reader)?;
+                    component.grow(rows, frame)?;
+                }
+            }
 
-                    // So number of quant tables is either 1 or 3
-                    let num_components = read_u8(&mut self.reader)?;
-                    assert!([1, 3].contains(&num_components));
+            let result = decode_mcu(
+                &mut self.components,
+                &scan_components,
+                (frame, scan),
+                (mcu % mcus_x, mcu / mcus_x),
+                &mut bitreader,
+                &mut state,
+            );
+
+            // a failed read ends the data early, which is not corrupt data
+            bitreader.take_error()?;
+
+            match result {
+                Ok(()) => mcu += 1,
+                // resume decoding at the next restart marker, the rest
+                // of the interval is left blank
+                Err(DecodeError::CorruptData(_) | DecodeError::Truncated)
+                    if restart_interval > 0 =>
+                {
+                    mcu = (mcu / restart_interval + 1) * restart_interval;
+                }
+                Err(e) => return Err(e),
+            }
 
-                    println!(" {}-bit precision", data_precision);
-                    println!(" Resolution: {width}x{height} px");
+            if restart_interval > 0 && mcu % restart_interval == 0 && mcu < mcus {
+                match bitreader.next_marker()? {
+                    marker @ 0xd0..=0xd7 => {
+                        // if restart markers are missing, the intervals
+                        // in between are left blank
+                        let skipped = (marker - 0xd0).wrapping_sub(next_restart) % 8;
+                        mcu += skipped as usize * restart_interval;
 
-                    out_file.write_all(format!("P6\n{width} {height}\n255\n").as_bytes())?;
+                        next_restart = (marker - 0xd0 + 1) % 8;
 
-                    self.d.w = width;
-                    self.d.h = height;
-                    if num_components == 1 {
-                        println!(" Monochrome (1 component)");
-                    } else {
-                        println!(" YCbCr or YIQ (3 components)");
+                        bitreader.clear_marker();
+                        state.reset(mcu / mcus_x);
                     }
+                    // the rest of the scan is missing
+                    _ => break,
+                }
+            }
+        }
 
-                    let comp_id = |id: u8| match id {
-                        1 => "Y",
-                        2 => "Cb",
-                        3 => "Cr",
-                        4 => "I",
-                        5 => "Q",
-                        _ => panic!("unknown component id"),
-                    };
+        // hand the marker after the scan back to the segment parser
+        match bitreader.next_marker() {
+            Ok(marker) => self.pending_marker = Some(u16::from_be_bytes([0xff, marker])),
+            // tolerate files that are missing the EOI marker
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {}
+            Err(e) => return Err(e.into()),
+        }
 
-                    let qt = |n: u8| match n {
-                        0 => "Luminance",
-                        1 => "Chrominance",
-                        _ => panic!("invalid quant table index"),
-                    };
+        Ok(())
+    }
+
+    /// Returns the color space of the image, and the pixel format it is
+    /// decoded to
+    fn output(&self) -> Result<(ColorSpace, PixelFormat), DecodeError> {
+        let frame = self.frame.as_ref().ok_or(DecodeError::Truncated)?;
+
+        // lossless images are always decoded to 16 bits, so no precision
+        // is lost by default
+        let wide = frame.precision > 8 || frame.process == CodingProcess::Lossless;
+
+        let color_space = self.color_space().ok_or(DecodeError::Truncated)?;
+
+        if color_space.components() != frame.components.len() {
+            return Err(DecodeError::Unsupported(
+                "color space does not match the number of components",
+            ));
+        }
 
-                    let dashes = || println!(" --------------------");
+        let native_format = match (color_space.components(), wide) {
+            (1, false) => PixelFormat::Gray8,
+            (4, false) => PixelFormat::Cmyk8,
+            (_, false) => PixelFormat::Rgb8,
+            (1, true) => PixelFormat::Gray16,
+            (4, true) => PixelFormat::Cmyk16,
+            (_, true) => PixelFormat::Rgb16,
+        };
+
+        let format = self.output_format.unwrap_or(native_format);
+
+        if format.components() == 4 && color_space.components() != 4 {
+            return Err(DecodeError::Unsupported(
+                "CMYK output of images without four components",
+            ));
+        }
 
-                    let mut buf = [0; 3];
-                    for _ in 0..num_components {
-                        self.reader.read_exact(&mut buf)?;
+        Ok((color_space, format))
+    }
 
-                        // TODO figure out how to use these
-                        let _vdec = buf[1] & 0xf;
-                        let _hdec = buf[1] & 0xf0;
+    /// Converts the decoded coefficients of all components to an image in
+    /// the requested output format
+    fn reconstruct(&self) -> Result<Image, DecodeError> {
+        let frame = self.frame.as_ref().ok_or(DecodeError::Truncated)?;
 
-                        dashes();
-                        println!("     Component ID: {} ({})", buf[0], comp_id(buf[0]));
+        if self.components.is_empty() {
+            return Err(DecodeError::Truncated);
+        }
 
-                        // TODO how exactly are you supposed to actually parse this sample factors stuff?
-                        // println!(" Sampling Factors: 4:{}:{}", vdec, hdec);
-                        // What ?
-                        println!(" Sampling Factors: {}", buf[1]);
-                        println!("      Quant Table: {}", qt(buf[2]));
-                        // TODO append this to some kind of variable, apparently we need it
-                        // something like quant_mapping, an array with [0,1,1]
+        if frame.height == 0 {
+            return Err(DecodeError::InvalidSegment(
+                "no DNL segment after the first scan",
+            ));
+        }
 
-                        quant_mapping.push(buf[2]);
+        let (color_space, format) = self.output()?;
+
+        // luma is all that is needed for grayscale output of YCbCr images
+        let needed = match (format.components(), color_space) {
+            (1, ColorSpace::YCbCr) => 1,
+            _ => self.components.len(),
+        };
+
+        let (h_max, v_max) = max_sampling(frame);
+        let unit = data_unit_size(frame);
+
+        // upsample every component to the size of the padded image
+        let planes = self.components[..needed]
+            .iter()
+            .map(|component| {
+                let plane = match frame.process {
+                    CodingProcess::Lossless => component.samples.clone(),
+                    _ => {
+                        let quant_matrix = self.quant_matrices[component.quant_table]
+                            .as_ref()
+                            .ok_or(DecodeError::MissingTable {
+                                kind: TableKind::Quantization,
+                                index: component.quant_table as u8,
+                            })?;
+
+                        component.to_samples(quant_matrix, frame.precision)
                     }
+                };
+
+                let size = (unit * component.blocks_w, unit * component.blocks_h);
+
+                // size of the component without the padding
+                let real_size = (component.width, component.height);
+
+                let plane = match (self.upsampling, h_max / component.h, v_max / component.v) {
+                    (_, 1, 1) => plane,
+                    (Upsampling::Fancy, 2, 1) => upsample_h2v1_fancy(&plane, size, real_size),
+                    (Upsampling::Fancy, 2, 2) => upsample_h2v2_fancy(&plane, size, real_size),
+                    (_, fx, fy) => upsample_box(&plane, size, (fx, fy)),
+                };
+
+                Ok(plane)
+            })
+            .collect::<Result<Vec<_>, DecodeError>>()?;
 
-                    dashes();
+        let stride = unit * self.components[0].blocks_w * h_max / self.components[0].h;
+
+        let (w, h) = (frame.width as usize, frame.height as usize);
+
+        let mut buf = vec![0; format.components() * w * h];
+
+        // Adobe files store inverted CMYK values
+        let transform = (color_space == ColorSpace::Ycck, self.adobe.is_some());
+
+        let precision = frame.precision;
+
+        match (format.components(), &planes[..]) {
+            (1, [y]) => to_gray((w, h), stride, y, &mut buf),
+            (3, [y]) => gray_to_rgb((w, h), stride, y, &mut buf),
+            (3, [y, cb, cr]) if color_space == ColorSpace::YCbCr => {
+                to_rgb((w, h), stride, [y, cb, cr], precision, &mut buf)
+            }
+            (3, [r, g, b]) => interleave((w, h), stride, &[r, g, b].map(Vec::as_slice), &mut buf),
+            (1, [r, g, b]) => {
+                let mut rgb = vec![0; 3 * w * h];
+                interleave((w, h), stride, &[r, g, b].map(Vec::as_slice), &mut rgb);
+                rgb_to_gray(&rgb, &mut buf);
+            }
+            (4, [c, m, y, k]) => {
+                to_cmyk((w, h), stride, [c, m, y, k], transform, precision, &mut buf)
+            }
+            (n, [c, m, y, k]) => {
+                let planes: [&[u16]; 4] = [c, m, y, k];
+
+                let mut cmyk = vec![0; 4 * w * h];
+                to_cmyk((w, h), stride, planes, transform, precision, &mut cmyk);
+
+                match n {
+                    3 => cmyk_to_rgb(&cmyk, precision, &mut buf),
+                    _ => {
+                        let mut rgb = vec![0; 3 * w * h];
+                        cmyk_to_rgb(&cmyk, precision, &mut rgb);
+                        rgb_to_gray(&rgb, &mut buf);
+                    }
                 }
-                _ => {
-                    // read another BE u16, which indicates the length
-                    let len = read_u16(&mut self.reader)?;
-
-                    // The readed length includes the size of itself,
-                    // but since we advanced the reader 2 bytes to actually
-                    // read the length, we need to subtract by 2 to seek
-                    // by the correct amount.
-                    self.reader.seek_relative((len - 2) as i64)?;
+            }
+            _ => unreachable!("number of planes is checked before decoding the scan"),
+        }
+
+        Ok(Image::from_samples(
+            (frame.width, frame.height),
+            format,
+            frame.precision,
+            &buf,
+        ))
+    }
+
+    /// Decodes the image. The coefficients of every component are collected
+    /// over all scans of the frame, which can each code any subset of the
+    /// components, and the image is reconstructed at the end of the data.
+    pub fn decode(&mut self) -> Result<Image, DecodeError> {
+        while let Some(segment) = self.read_segment()? {
+            match segment.contents {
+                SegmentContents::Empty if segment.marker == JpegMarker::EndOfImage => break,
+                // Start of scan (actual entropy coded image data)
+                SegmentContents::Scan(scan) => {
+                    let mut tables = self.huffman_table.iter().flatten();
+
+                    if !tables.any(HuffmanTree::is_defined) {
+                        self.set_default_huffman_tables();
+                    }
+
+                    self.decode_scan(&scan)?
                 }
+                contents => self.apply_segment(contents)?,
             }
         }
 
-        let mut buf = vec![0; 3 * self.d.w as usize * self.d.h as usize];
+        self.reconstruct()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decoder that has read a frame header with components of the given
+    /// IDs, and the given JFIF and Adobe headers
+    fn decoder(ids: &[u8], jfif: bool, transform: Option<u8>) -> Decoder<&'static [u8]> {
+        let components = ids
+            .iter()
+            .map(|&id| FrameComponent {
+                id,
+                h_sampling: 1,
+                v_sampling: 1,
+                quant_table: 0,
+            })
+            .collect();
+
+        let mut decoder = Decoder::new(&[][..]);
+
+        decoder.frame = Some(Frame {
+            process: CodingProcess::Baseline,
+            entropy_coding: EntropyCoding::Huffman,
+            precision: 8,
+            height: 8,
+            width: 8,
+            components,
+        });
+        decoder.jfif = jfif;
+        decoder.adobe = transform.map(|transform| Adobe {
+            version: 100,
+            flags0: 0,
+            flags1: 0,
+            transform,
+        });
+
+        decoder
+    }
 
-        to_rgb((self.d.w, self.d.h), &mut buf, &blocks);
+    fn color_space(ids: &[u8], jfif: bool, transform: Option<u8>) -> Option<ColorSpace> {
+        decoder(ids, jfif, transform).color_space()
+    }
 
-        out_file.write_all(&buf).unwrap();
+    #[test]
+    fn unknown_before_frame_header() {
+        assert_eq!(Decoder::new(&[][..]).color_space(), None);
+    }
 
-        Ok(())
+    #[test]
+    fn one_component_is_gray() {
+        assert_eq!(color_space(&[1], false, None), Some(ColorSpace::Gray));
+        assert_eq!(color_space(&[1], true, Some(1)), Some(ColorSpace::Gray));
+    }
+
+    #[test]
+    fn jfif_implies_ycbcr() {
+        assert_eq!(color_space(b"RGB", true, None), Some(ColorSpace::YCbCr));
+        assert_eq!(color_space(b"RGB", true, Some(0)), Some(ColorSpace::YCbCr));
+    }
+
+    #[test]
+    fn adobe_transform_of_three_components() {
+        assert_eq!(
+            color_space(&[1, 2, 3], false, Some(0)),
+            Some(ColorSpace::Rgb)
+        );
+        assert_eq!(color_space(b"RGB", false, Some(1)), Some(ColorSpace::YCbCr));
+        assert_eq!(color_space(b"RGB", false, Some(2)), Some(ColorSpace::YCbCr));
+    }
+
+    #[test]
+    fn component_ids_without_headers() {
+        assert_eq!(color_space(b"RGB", false, None), Some(ColorSpace::Rgb));
+        assert_eq!(color_space(b"rgb", false, None), Some(ColorSpace::YCbCr));
+        assert_eq!(
+            color_space(&[1, 2, 3], false, None),
+            Some(ColorSpace::YCbCr)
+        );
+    }
+
+    #[test]
+    fn adobe_transform_of_four_components() {
+        let ids = [1, 2, 3, 4];
+
+        assert_eq!(color_space(&ids, false, None), Some(ColorSpace::Cmyk));
+        assert_eq!(color_space(&ids, false, Some(0)), Some(ColorSpace::Cmyk));
+        assert_eq!(color_space(&ids, false, Some(1)), Some(ColorSpace::Ycck));
+        assert_eq!(color_space(&ids, false, Some(2)), Some(ColorSpace::Ycck));
+    }
+
+    #[test]
+    fn unsupported_numbers_of_components() {
+        assert_eq!(color_space(&[1, 2], false, None), None);
+        assert_eq!(color_space(&[1, 2, 3, 4, 5], false, Some(0)), None);
+    }
+
+    #[test]
+    fn override_takes_precedence() {
+        let mut decoder = decoder(&[1, 2, 3], true, Some(1));
+        decoder.set_color_space(ColorSpace::Rgb);
+
+        assert_eq!(decoder.color_space(), Some(ColorSpace::Rgb));
     }
 }
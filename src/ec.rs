@@ -1,51 +1,425 @@
+use std::io::BufRead;
+
 use crate::bitstream::BitReader;
+use crate::error::DecodeError;
+
+/// Number of bits looked up at once when decoding a huffman code. Longer
+/// codes are rare, and decoded one length at a time.
+const LOOKUP_BITS: u32 = 9;
+
+#[derive(Copy, Clone, Default)]
+struct LookupEntry {
+    symbol: u8,
+    /// Length of the code, 0 if it is longer than `LOOKUP_BITS`
+    len: u8,
+    /// Length of the code and the magnitude bits that follow it, 0 if they
+    /// don't fit in `LOOKUP_BITS`
+    value_len: u8,
+    /// Magnitude bits of the low nibble of the symbol, already extended
+    value: i16,
+}
 
 pub(crate) struct HuffmanTree {
     pub symbols: Box<[u8]>,
-    pub cht: Box<[(u16, u8, u8)]>,
-    // min code length
-    pub l0: u8,
+    /// Indexed by the next `LOOKUP_BITS` bits of the data
+    lookup: Box<[LookupEntry]>,
+    /// Largest code of each length, -1 if there are none
+    max_code: [i32; 17],
+    /// Index into `symbols` minus the code, for every code length
+    val_offset: [i32; 17],
 }
 
 impl HuffmanTree {
     pub fn new() -> Self {
         Self {
-            cht: Box::new([]),
             symbols: Box::new([]),
-            l0: 0,
+            lookup: vec![LookupEntry::default(); 1 << LOOKUP_BITS].into_boxed_slice(),
+            max_code: [-1; 17],
+            val_offset: [0; 17],
+        }
+    }
+
+    /// Builds the canonical huffman code from the number of codes of each
+    /// length (1 to 16 bits) and the symbols in order of increasing code length.
+    /// The code lengths must not be over-subscribed, which DHT parsing checks.
+    pub fn from_table(counts: &[u8; 16], symbols: &[u8]) -> Self {
+        let mut tree = Self::new();
+
+        let mut code = 0u32;
+        let mut idx = 0;
+
+        for (len, &count) in (1..=16).zip(counts) {
+            tree.val_offset[len as usize] = idx as i32 - code as i32;
+
+            for &symbol in &symbols[idx..][..count as usize] {
+                if len <= LOOKUP_BITS {
+                    let shift = LOOKUP_BITS - len;
+                    let first = (code << shift) as usize;
+
+                    for (i, entry) in tree.lookup[first..][..1 << shift].iter_mut().enumerate() {
+                        *entry = LookupEntry::new(symbol, len, i as u32, shift);
+                    }
+                }
+
+                code += 1;
+            }
+
+            idx += count as usize;
+
+            if count > 0 {
+                tree.max_code[len as usize] = code as i32 - 1;
+            }
+
+            code <<= 1;
+        }
+
+        tree.symbols = symbols.into();
+        tree
+    }
+
+    pub fn is_defined(&self) -> bool {
+        !self.symbols.is_empty()
+    }
+
+    /// Returns `None` if the bits do not form a valid code for this table
+    pub fn read_code<R: BufRead>(&self, bitreader: &mut BitReader<R>) -> Option<u8> {
+        let w = bitreader.peek_bits::<LOOKUP_BITS>();
+        let entry = self.lookup[w as usize];
+
+        if entry.len > 0 {
+            bitreader.consume_bits(entry.len as u32);
+            return Some(entry.symbol);
+        }
+
+        // the code is longer than the lookup, or invalid
+        let w = bitreader.peek_bits::<16>() as i32;
+
+        for len in LOOKUP_BITS + 1..=16 {
+            let code = w >> (16 - len);
+
+            if code <= self.max_code[len as usize] {
+                bitreader.consume_bits(len);
+
+                let idx = usize::try_from(code + self.val_offset[len as usize]).ok()?;
+                return self.symbols.get(idx).copied();
+            }
+        }
+
+        None
+    }
+
+    /// Reads a code and the magnitude bits that follow it. Returns the
+    /// symbol and the value of the bits, extended to a signed number of
+    /// as many bits as the low nibble of the symbol (F.2.2.1).
+    pub fn read_value<R: BufRead>(
+        &self,
+        bitreader: &mut BitReader<R>,
+    ) -> Result<(u8, i16), DecodeError> {
+        let w = bitreader.peek_bits::<LOOKUP_BITS>();
+        let entry = self.lookup[w as usize];
+
+        // the data could end in the middle of the magnitude bits, which is
+        // left to the slow path
+        if entry.value_len > 0 && entry.value_len as u32 <= bitreader.cached_bits() {
+            bitreader.consume_bits(entry.value_len as u32);
+            return Ok((entry.symbol, entry.value));
+        }
+
+        let symbol = self
+            .read_code(bitreader)
+            .ok_or(DecodeError::CorruptData("invalid huffman code"))?;
+
+        let size = (symbol & 0xf) as u32;
+
+        let bits = bitreader.get_n_bits(size).ok_or(DecodeError::Truncated)?;
+
+        Ok((symbol, sign_code(size, bits)))
+    }
+}
+
+impl LookupEntry {
+    /// Entry of a code of `len` bits, followed by `extra` bits of other
+    /// codes that are `shift` bits long
+    fn new(symbol: u8, len: u32, extra: u32, shift: u32) -> Self {
+        let size = (symbol & 0xf) as u32;
+
+        let (value_len, value) = match size <= shift {
+            true => {
+                let bits = (extra >> (shift - size)) & ((1 << size) - 1);
+                ((len + size) as u8, sign_code(size, bits as u16))
+            }
+            false => (0, 0),
+        };
+
+        Self {
+            symbol,
+            len: len as u8,
+            value_len,
+            value,
         }
     }
+}
 
-    pub fn read_code(&self, bitreader: &mut BitReader) -> Option<u8> {
-        let mut w = bitreader.peek_bits::<16>()?;
+/// Example tables of Annex K.3, as code counts of every length and symbols.
+/// Motion-JPEG frames usually leave out their DHT segments and use these.
+#[rustfmt::skip]
+pub(crate) static DEFAULT_DC_TABLES: [([u8; 16], &[u8]); 2] = [
+    // luminance
+    ([0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+    // chrominance
+    ([0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+];
 
-        if w < self.cht[0].0 {
-            w >>= 16 - self.l0;
+#[rustfmt::skip]
+pub(crate) static DEFAULT_AC_TABLES: [([u8; 16], &[u8]); 2] = [
+    ([0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d], &AC_LUMINANCE_SYMBOLS),
+    ([0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77], &AC_CHROMINANCE_SYMBOLS),
+];
 
-            bitreader.consume_bits(self.l0 as u32);
+#[rustfmt::skip]
+static AC_LUMINANCE_SYMBOLS: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06,
+    0x13, 0x51, 0x61, 0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08,
+    0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72,
+    0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45,
+    0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59,
+    0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75,
+    0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3,
+    0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6,
+    0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9,
+    0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4,
+    0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
 
-            Some(self.symbols[w as usize])
-        } else {
-            // TODO rewrite as functional
-            let mut j = None;
-            for i in 1..self.cht.len() {
-                if self.cht[i].0 > w {
-                    j = Some(i - 1);
-                    break;
+#[rustfmt::skip]
+static AC_CHROMINANCE_SYMBOLS: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41,
+    0x51, 0x07, 0x61, 0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91,
+    0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0, 0x15, 0x62, 0x72, 0xd1,
+    0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44,
+    0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58,
+    0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74,
+    0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a,
+    0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4,
+    0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+    0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4,
+    0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
+
+/// Probability estimation state machine of the QM-coder (Table D.2):
+/// Qe value, next state after an LPS, next state after an MPS, and whether
+/// an LPS switches the sense of the MPS
+#[rustfmt::skip]
+static QE_TABLE: [(u16, u8, u8, bool); 114] = [
+    (0x5a1d,   1,   1, true),
+    (0x2586,  14,   2, false),
+    (0x1114,  16,   3, false),
+    (0x080b,  18,   4, false),
+    (0x03d8,  20,   5, false),
+    (0x01da,  23,   6, false),
+    (0x00e5,  25,   7, false),
+    (0x006f,  28,   8, false),
+    (0x0036,  30,   9, false),
+    (0x001a,  33,  10, false),
+    (0x000d,  35,  11, false),
+    (0x0006,   9,  12, false),
+    (0x0003,  10,  13, false),
+    (0x0001,  12,  13, false),
+    (0x5a7f,  15,  15, true),
+    (0x3f25,  36,  16, false),
+    (0x2cf2,  38,  17, false),
+    (0x207c,  39,  18, false),
+    (0x17b9,  40,  19, false),
+    (0x1182,  42,  20, false),
+    (0x0cef,  43,  21, false),
+    (0x09a1,  45,  22, false),
+    (0x072f,  46,  23, false),
+    (0x055c,  48,  24, false),
+    (0x0406,  49,  25, false),
+    (0x0303,  51,  26, false),
+    (0x0240,  52,  27, false),
+    (0x01b1,  54,  28, false),
+    (0x0144,  56,  29, false),
+    (0x00f5,  57,  30, false),
+    (0x00b7,  59,  31, false),
+    (0x008a,  60,  32, false),
+    (0x0068,  62,  33, false),
+    (0x004e,  63,  34, false),
+    (0x003b,  32,  35, false),
+    (0x002c,  33,   9, false),
+    (0x5ae1,  37,  37, true),
+    (0x484c,  64,  38, false),
+    (0x3a0d,  65,  39, false),
+    (0x2ef1,  67,  40, false),
+    (0x261f,  68,  41, false),
+    (0x1f33,  69,  42, false),
+    (0x19a8,  70,  43, false),
+    (0x1518,  72,  44, false),
+    (0x1177,  73,  45, false),
+    (0x0e74,  74,  46, false),
+    (0x0bfb,  75,  47, false),
+    (0x09f8,  77,  48, false),
+    (0x0861,  78,  49, false),
+    (0x0706,  79,  50, false),
+    (0x05cd,  48,  51, false),
+    (0x04de,  50,  52, false),
+    (0x040f,  50,  53, false),
+    (0x0363,  51,  54, false),
+    (0x02d4,  52,  55, false),
+    (0x025c,  53,  56, false),
+    (0x01f8,  54,  57, false),
+    (0x01a4,  55,  58, false),
+    (0x0160,  56,  59, false),
+    (0x0125,  57,  60, false),
+    (0x00f6,  58,  61, false),
+    (0x00cb,  59,  62, false),
+    (0x00ab,  61,  63, false),
+    (0x008f,  61,  32, false),
+    (0x5b12,  65,  65, true),
+    (0x4d04,  80,  66, false),
+    (0x412c,  81,  67, false),
+    (0x37d8,  82,  68, false),
+    (0x2fe8,  83,  69, false),
+    (0x293c,  84,  70, false),
+    (0x2379,  86,  71, false),
+    (0x1edf,  87,  72, false),
+    (0x1aa9,  87,  73, false),
+    (0x174e,  72,  74, false),
+    (0x1424,  72,  75, false),
+    (0x119c,  74,  76, false),
+    (0x0f6b,  74,  77, false),
+    (0x0d51,  75,  78, false),
+    (0x0bb6,  77,  79, false),
+    (0x0a40,  77,  48, false),
+    (0x5832,  80,  81, true),
+    (0x4d1c,  88,  82, false),
+    (0x438e,  89,  83, false),
+    (0x3bdd,  90,  84, false),
+    (0x34ee,  91,  85, false),
+    (0x2eae,  92,  86, false),
+    (0x299a,  93,  87, false),
+    (0x2516,  86,  71, false),
+    (0x5570,  88,  89, true),
+    (0x4ca9,  95,  90, false),
+    (0x44d9,  96,  91, false),
+    (0x3e22,  97,  92, false),
+    (0x3824,  99,  93, false),
+    (0x32b4,  99,  94, false),
+    (0x2e17,  93,  86, false),
+    (0x56a8,  95,  96, true),
+    (0x4f46, 101,  97, false),
+    (0x47e5, 102,  98, false),
+    (0x41cf, 103,  99, false),
+    (0x3c3d, 104, 100, false),
+    (0x375e,  99,  93, false),
+    (0x5231, 105, 102, false),
+    (0x4c0f, 106, 103, false),
+    (0x4639, 107, 104, false),
+    (0x415e, 103,  99, false),
+    (0x5627, 105, 106, true),
+    (0x50e7, 108, 107, false),
+    (0x4b85, 109, 103, false),
+    (0x5597, 110, 109, false),
+    (0x504f, 111, 107, false),
+    (0x5a10, 110, 111, true),
+    (0x5522, 112, 109, false),
+    (0x59eb, 112, 111, true),
+    (0x5a1d, 113, 113, false),
+];
+
+/// State of a statistics bin that always estimates a probability of 0.5.
+/// Not part of Table D.2, it is used for sign and correction bits.
+pub const FIXED_BIN: u8 = 113;
+
+/// QM-coder, the adaptive binary arithmetic decoder of Annex D
+///
+/// Every decision is decoded with a statistics bin, a byte that holds the
+/// index into [`QE_TABLE`] in the low 7 bits, and the value of the more
+/// probable symbol (MPS) in the top bit.
+pub(crate) struct ArithmeticDecoder {
+    // code register
+    c: u32,
+    // probability interval
+    a: u32,
+    // bits left in the low byte of c, negative while the first two bytes
+    // are read
+    ct: i32,
+}
+
+impl ArithmeticDecoder {
+    pub fn new() -> Self {
+        Self {
+            c: 0,
+            a: 0,
+            // forces two bytes to be read before the first decision
+            ct: -16,
+        }
+    }
+
+    /// Decodes one binary decision, and updates the estimate in `bin`
+    pub fn decode<R: BufRead>(&mut self, bitreader: &mut BitReader<R>, bin: &mut u8) -> bool {
+        // renormalization, reads zeros after a marker or the end of the data
+        while self.a < 0x8000 {
+            self.ct -= 1;
+
+            if self.ct < 0 {
+                let byte = bitreader.byte_refill().unwrap_or(0);
+
+                self.c = (self.c << 8) | byte as u32;
+                self.ct += 8;
+
+                if self.ct < 0 {
+                    self.ct += 1;
+
+                    // both initial bytes are in, a becomes 0x10000 below
+                    if self.ct == 0 {
+                        self.a = 0x8000;
+                    }
                 }
             }
-            let j = j.unwrap_or(self.cht.len() - 1);
 
-            // aug_c = augmented codeword
-            let (aug_c, l, offset) = self.cht[j];
+            self.a <<= 1;
+        }
+
+        let state = *bin;
+        let (qe, next_lps, next_mps, switch) = QE_TABLE[(state & 0x7f) as usize];
+        let qe = qe as u32;
+        let mut mps = state >> 7;
+
+        let after_lps = |mps: u8| (mps ^ switch as u8) << 7 | next_lps;
+        let after_mps = |mps: u8| mps << 7 | next_mps;
 
-            w >>= 16 - l;
+        self.a -= qe;
+        let temp = self.a << self.ct;
 
-            bitreader.consume_bits(l as u32);
+        if self.c >= temp {
+            self.c -= temp;
 
-            let base = aug_c >> (16 - l);
-            Some(self.symbols[w as usize - base as usize + offset as usize])
+            // conditional exchange, the LPS subinterval is larger
+            if self.a < qe {
+                *bin = after_mps(mps);
+            } else {
+                *bin = after_lps(mps);
+                mps ^= 1;
+            }
+
+            self.a = qe;
+        } else if self.a < 0x8000 {
+            if self.a < qe {
+                *bin = after_lps(mps);
+                mps ^= 1;
+            } else {
+                *bin = after_mps(mps);
+            }
         }
+
+        mps != 0
     }
 }
 
@@ -54,6 +428,6 @@ pub fn sign_code(n_bits: u32, code: u16) -> i16 {
         code as i16
     } else {
         let max_val = (1 << n_bits) - 1;
-        code as i16 - max_val
+        (code as i32 - max_val) as i16
     }
 }
@@ -1,12 +1,108 @@
-use std::io;
+use std::fmt::{self, Display};
+use std::io::{self, ErrorKind};
+
+/// Kind of table referenced by a [`DecodeError`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TableKind {
+    Quantization,
+    DcHuffman,
+    AcHuffman,
+    DcConditioning,
+    AcConditioning,
+}
+
+impl Display for TableKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TableKind::Quantization => "quantization",
+            TableKind::DcHuffman => "DC huffman",
+            TableKind::AcHuffman => "AC huffman",
+            TableKind::DcConditioning => "DC arithmetic conditioning",
+            TableKind::AcConditioning => "AC arithmetic conditioning",
+        })
+    }
+}
 
 #[derive(Debug)]
 pub enum DecodeError {
+    /// The underlying reader failed
     Io(io::Error),
+    /// The data ended before the image was fully decoded
+    Truncated,
+    /// A marker was found that is not valid at this point of the file.
+    /// `offset` is the position of the marker from the start of the data.
+    UnknownMarker { marker: u16, offset: u64 },
+    /// A table destination outside of the range allowed by the specification
+    InvalidTableIndex { kind: TableKind, index: u8 },
+    /// A scan uses a table that was never defined
+    MissingTable { kind: TableKind, index: u8 },
+    /// A huffman table does not describe a valid code, or has symbols that
+    /// can't be decoded in the frame that uses it
+    InvalidHuffmanTable {
+        kind: TableKind,
+        index: u8,
+        reason: &'static str,
+    },
+    /// Decoding the image needs more memory than the limit set with
+    /// `Decoder::set_max_memory`
+    LimitExceeded { required: u64, limit: u64 },
+    /// Memory for the image could not be allocated
+    OutOfMemory,
+    /// The file uses a JPEG feature that is not implemented
+    Unsupported(&'static str),
+    /// A marker segment contains invalid values
+    InvalidSegment(&'static str),
+    /// The entropy-coded data of a scan could not be decoded
+    CorruptData(&'static str),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Io(err) => write!(f, "I/O error: {err}"),
+            DecodeError::Truncated => f.write_str("unexpected end of data"),
+            DecodeError::UnknownMarker { marker, offset } => {
+                write!(f, "unknown marker 0x{marker:04x} at offset {offset}")
+            }
+            DecodeError::InvalidTableIndex { kind, index } => {
+                write!(f, "invalid {kind} table index {index}")
+            }
+            DecodeError::MissingTable { kind, index } => {
+                write!(f, "{kind} table {index} is used but not defined")
+            }
+            DecodeError::InvalidHuffmanTable {
+                kind,
+                index,
+                reason,
+            } => write!(f, "invalid {kind} table {index}: {reason}"),
+            DecodeError::LimitExceeded { required, limit } => {
+                write!(
+                    f,
+                    "image needs {required} bytes, more than the limit of {limit}"
+                )
+            }
+            DecodeError::OutOfMemory => f.write_str("out of memory"),
+            DecodeError::Unsupported(feature) => write!(f, "unsupported feature: {feature}"),
+            DecodeError::InvalidSegment(reason) => write!(f, "invalid segment: {reason}"),
+            DecodeError::CorruptData(reason) => write!(f, "corrupt entropy-coded data: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for DecodeError {
     fn from(error: io::Error) -> Self {
-        Self::Io(error)
+        match error.kind() {
+            ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(error),
+        }
     }
 }
@@ -0,0 +1,124 @@
+use std::io::{self, Write};
+
+/// Memory layout of the pixels in an [`Image`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 8-bit grayscale, 1 byte per pixel
+    Gray8,
+    /// 8-bit interleaved RGB, 3 bytes per pixel
+    Rgb8,
+    /// Grayscale with up to 16 bits per sample, 2 bytes per pixel
+    Gray16,
+    /// Interleaved RGB with up to 16 bits per sample, 6 bytes per pixel
+    Rgb16,
+    /// 8-bit interleaved CMYK, 4 bytes per pixel. 0 is no ink, 255 is full
+    /// coverage.
+    Cmyk8,
+    /// Interleaved CMYK with up to 16 bits per sample, 8 bytes per pixel
+    Cmyk16,
+}
+
+impl PixelFormat {
+    /// Number of interleaved samples per pixel
+    pub fn components(self) -> usize {
+        match self {
+            PixelFormat::Gray8 | PixelFormat::Gray16 => 1,
+            PixelFormat::Rgb8 | PixelFormat::Rgb16 => 3,
+            PixelFormat::Cmyk8 | PixelFormat::Cmyk16 => 4,
+        }
+    }
+
+    /// Number of bytes used to store a single sample
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            PixelFormat::Gray8 | PixelFormat::Rgb8 | PixelFormat::Cmyk8 => 1,
+            PixelFormat::Gray16 | PixelFormat::Rgb16 | PixelFormat::Cmyk16 => 2,
+        }
+    }
+
+    /// Number of bytes used to store a single pixel
+    pub fn bytes_per_pixel(self) -> usize {
+        self.components() * self.bytes_per_sample()
+    }
+}
+
+/// A fully decoded image
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub width: u16,
+    pub height: u16,
+    pub format: PixelFormat,
+    /// Number of significant bits of every sample. Always 8 for the 8-bit
+    /// formats, samples of the 16-bit formats range from 0 to
+    /// `2^precision - 1`.
+    pub precision: u8,
+    /// Pixels in row-major order, without any padding between rows.
+    /// Samples of the 16-bit formats are stored in native byte order.
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Packs interleaved samples of `precision` bits into an image of the
+    /// given format. 8-bit formats get the top 8 bits of every sample.
+    pub(crate) fn from_samples(
+        (width, height): (u16, u16),
+        format: PixelFormat,
+        precision: u8,
+        samples: &[u16],
+    ) -> Self {
+        let (data, precision) = match format.bytes_per_sample() {
+            1 if precision >= 8 => {
+                let shift = precision - 8;
+                (samples.iter().map(|&s| (s >> shift) as u8).collect(), 8)
+            }
+            1 => {
+                let shift = 8 - precision;
+                (samples.iter().map(|&s| (s << shift) as u8).collect(), 8)
+            }
+            _ => (
+                samples.iter().flat_map(|s| s.to_ne_bytes()).collect(),
+                precision,
+            ),
+        };
+
+        Image {
+            width,
+            height,
+            format,
+            precision,
+            data,
+        }
+    }
+
+    /// Writes the image as a binary PNM file (P5 for grayscale, P6 for RGB,
+    /// and a P7 PAM file for CMYK).
+    pub fn write_pnm<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let Image { width, height, .. } = self;
+
+        let max_value = (1u32 << self.precision) - 1;
+
+        let header = match self.format.components() {
+            1 => format!("P5\n{width} {height}\n{max_value}\n"),
+            3 => format!("P6\n{width} {height}\n{max_value}\n"),
+            depth => format!(
+                "P7\nWIDTH {width}\nHEIGHT {height}\nDEPTH {depth}\nMAXVAL {max_value}\n\
+                 TUPLTYPE CMYK\nENDHDR\n"
+            ),
+        };
+
+        writer.write_all(header.as_bytes())?;
+
+        let samples = self
+            .data
+            .chunks_exact(2)
+            .map(|sample| u16::from_ne_bytes([sample[0], sample[1]]));
+
+        match (self.format.bytes_per_sample(), max_value) {
+            (1, _) => writer.write_all(&self.data),
+            // PNM uses a single byte per sample if the maximum fits in it
+            (_, 0..=255) => writer.write_all(&samples.map(|s| s as u8).collect::<Vec<_>>()),
+            // wider samples are big endian
+            _ => writer.write_all(&samples.flat_map(u16::to_be_bytes).collect::<Vec<_>>()),
+        }
+    }
+}
@@ -2,11 +2,21 @@
 
 //! zen-jpeg, a JPEG decoder
 
+pub use crate::color::ColorSpace;
 pub use crate::decoder::Decoder;
+pub use crate::image::{Image, PixelFormat};
+pub use crate::upsample::Upsampling;
 
 mod decoder;
 
+mod arithmetic;
 mod bitstream;
+mod color;
 mod dct;
 mod ec;
 pub mod error;
+mod image;
+mod lossless;
+mod progressive;
+pub mod segment;
+mod upsample;
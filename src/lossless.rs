@@ -0,0 +1,161 @@
+//! Sample prediction for lossless (SOF3) frames
+//!
+//! Every sample is coded as the difference to a prediction from its left
+//! (`ra`), upper (`rb`) and upper left (`rc`) neighbors, using the DC
+//! huffman tables.
+
+use std::io::BufRead;
+
+use crate::bitstream::BitReader;
+use crate::ec::{sign_code, HuffmanTree};
+use crate::error::DecodeError;
+
+/// Reads the difference between a sample and its prediction
+pub fn read_difference<R: BufRead>(
+    huff_tree: &HuffmanTree,
+    bitreader: &mut BitReader<R>,
+) -> Result<i32, DecodeError> {
+    let bits = huff_tree
+        .read_code(bitreader)
+        .ok_or(DecodeError::CorruptData("invalid lossless huffman code"))?;
+
+    match bits {
+        0 => Ok(0),
+        // no extra bits follow the largest difference
+        16 => Ok(32768),
+        1..=15 => {
+            let value = bitreader
+                .get_n_bits(bits as u32)
+                .ok_or(DecodeError::Truncated)?;
+
+            Ok(sign_code(bits as u32, value) as i32)
+        }
+        _ => Err(DecodeError::CorruptData("lossless difference out of range")),
+    }
+}
+
+/// Prediction parameters of a lossless scan
+pub struct Predictor {
+    /// Predictor selection value (Ss), 1 to 7
+    selection: u8,
+    /// Point transform (Al), the number of low bits that are not coded
+    point_transform: u8,
+    precision: u8,
+}
+
+impl Predictor {
+    pub fn new(selection: u8, point_transform: u8, precision: u8) -> Self {
+        Self {
+            selection,
+            point_transform,
+            precision,
+        }
+    }
+
+    /// Predicts the sample at `(x, y)` of a plane from the samples that are
+    /// already decoded. `first_line` is set for the first line of the scan
+    /// and the first line after every restart marker, which have no upper
+    /// neighbors.
+    pub fn predict(
+        &self,
+        plane: &[u16],
+        stride: usize,
+        (x, y): (usize, usize),
+        first_line: bool,
+    ) -> i32 {
+        // samples are stored with the point transform undone
+        let sample = |x: usize, y: usize| (plane[y * stride + x] >> self.point_transform) as i32;
+
+        match (x, first_line) {
+            (0, true) => 1 << (self.precision - self.point_transform - 1),
+            (_, true) => sample(x - 1, y),
+            (0, false) => sample(x, y - 1),
+            (_, false) => {
+                let ra = sample(x - 1, y);
+                let rb = sample(x, y - 1);
+                let rc = sample(x - 1, y - 1);
+
+                match self.selection {
+                    1 => ra,
+                    2 => rb,
+                    3 => rc,
+                    4 => ra + rb - rc,
+                    5 => ra + ((rb - rc) >> 1),
+                    6 => rb + ((ra - rc) >> 1),
+                    _ => (ra + rb) / 2,
+                }
+            }
+        }
+    }
+
+    /// Adds the difference to the prediction, modulo 2^16, and undoes the
+    /// point transform
+    pub fn reconstruct(&self, prediction: i32, difference: i32) -> u16 {
+        ((prediction + difference) as u16) << self.point_transform
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Predicts the lower right sample of a 2x2 plane
+    fn predict(selection: u8, (ra, rb, rc): (u16, u16, u16)) -> i32 {
+        let plane = [rc, rb, ra, 0];
+
+        Predictor::new(selection, 0, 8).predict(&plane, 2, (1, 1), false)
+    }
+
+    #[test]
+    fn predicts_from_neighbors() {
+        let neighbors = (20, 30, 10);
+
+        let predictions = (1..=7).map(|selection| predict(selection, neighbors));
+
+        assert!(predictions.eq([20, 30, 10, 40, 30, 35, 25]));
+    }
+
+    #[test]
+    fn rounds_halved_gradients_down() {
+        let neighbors = (20, 10, 31);
+
+        assert_eq!(predict(4, neighbors), -1);
+        // 20 + (-21 >> 1) and 10 + (-11 >> 1)
+        assert_eq!(predict(5, neighbors), 9);
+        assert_eq!(predict(6, neighbors), 4);
+    }
+
+    #[test]
+    fn predicts_edges_from_a_single_neighbor() {
+        let plane = [5, 6, 7, 8];
+        let predictor = Predictor::new(7, 0, 8);
+
+        assert_eq!(predictor.predict(&plane, 2, (0, 0), true), 128);
+        assert_eq!(predictor.predict(&plane, 2, (1, 0), true), 5);
+        assert_eq!(predictor.predict(&plane, 2, (0, 1), false), 5);
+        // after a restart marker, the line above is not used
+        assert_eq!(predictor.predict(&plane, 2, (1, 1), true), 7);
+    }
+
+    #[test]
+    fn applies_point_transform() {
+        let predictor = Predictor::new(1, 2, 12);
+
+        // the first sample is predicted at half the reduced range
+        assert_eq!(predictor.predict(&[0], 1, (0, 0), true), 512);
+
+        // samples are stored shifted back to the full range
+        let plane = [400, 0];
+        assert_eq!(predictor.predict(&plane, 2, (1, 0), true), 100);
+        assert_eq!(predictor.reconstruct(100, 5), 420);
+    }
+
+    #[test]
+    fn reconstructs_modulo_2_16() {
+        let predictor = Predictor::new(1, 0, 16);
+
+        assert_eq!(predictor.reconstruct(65535, 1), 0);
+        assert_eq!(predictor.reconstruct(0, -1), 65535);
+        assert_eq!(predictor.reconstruct(40000, 32768), 7232);
+    }
+}
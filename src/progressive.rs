@@ -0,0 +1,267 @@
+//! Block decoding for the scans of progressive frames
+//!
+//! Coefficients are kept quantized and in zigzag order, like in sequential
+//! scans. Every scan either codes the DC coefficients of all components
+//! (possibly interleaved), or a band of AC coefficients of one component.
+
+use std::io::BufRead;
+
+use crate::bitstream::BitReader;
+use crate::ec::HuffmanTree;
+use crate::error::DecodeError;
+
+fn read_bit<R: BufRead>(bitreader: &mut BitReader<R>) -> Result<bool, DecodeError> {
+    let bit = bitreader.get_n_bits(1).ok_or(DecodeError::Truncated)?;
+
+    Ok(bit != 0)
+}
+
+/// Reads the length of an EOB run, `r` is the high nibble of the symbol
+fn read_eob_run<R: BufRead>(bitreader: &mut BitReader<R>, r: u8) -> Result<u16, DecodeError> {
+    let extra = bitreader
+        .get_n_bits(r as u32)
+        .ok_or(DecodeError::Truncated)?;
+
+    // the run includes the current block
+    Ok((1 << r) + extra - 1)
+}
+
+/// First scan of the DC coefficients
+pub fn decode_dc_first<R: BufRead>(
+    block: &mut [i16; 64],
+    dc_huff_tree: &HuffmanTree,
+    bitreader: &mut BitReader<R>,
+    dc_pred: &mut i16,
+    approx_low: u8,
+) -> Result<(), DecodeError> {
+    let (dc_bits, dc_diff) = dc_huff_tree.read_value(bitreader)?;
+
+    if dc_bits > 15 {
+        return Err(DecodeError::CorruptData("DC difference out of range"));
+    }
+
+    *dc_pred = dc_pred.wrapping_add(dc_diff);
+
+    block[0] = dc_pred.wrapping_shl(approx_low as u32);
+
+    Ok(())
+}
+
+/// Refines the DC coefficient by one bit
+pub fn decode_dc_refine<R: BufRead>(
+    block: &mut [i16; 64],
+    bitreader: &mut BitReader<R>,
+    approx_low: u8,
+) -> Result<(), DecodeError> {
+    if read_bit(bitreader)? {
+        block[0] |= 1 << approx_low;
+    }
+
+    Ok(())
+}
+
+/// First scan of the AC coefficients `start..=end`
+pub fn decode_ac_first<R: BufRead>(
+    block: &mut [i16; 64],
+    ac_huff_tree: &HuffmanTree,
+    bitreader: &mut BitReader<R>,
+    (start, end): (u8, u8),
+    approx_low: u8,
+    eob_run: &mut u16,
+) -> Result<(), DecodeError> {
+    // block is part of an EOB run, all of the band is zero
+    if *eob_run > 0 {
+        *eob_run -= 1;
+        return Ok(());
+    }
+
+    let mut idx = start as usize;
+
+    while idx <= end as usize {
+        let (symbol, ac_coeff) = ac_huff_tree.read_value(bitreader)?;
+
+        let run_length = symbol >> 4;
+
+        if symbol & 0xf == 0 {
+            if run_length < 15 {
+                *eob_run = read_eob_run(bitreader, run_length)?;
+                break;
+            }
+
+            // ZRL, 16 zeros
+            idx += 16;
+            continue;
+        }
+
+        idx += run_length as usize;
+
+        if idx > end as usize {
+            return Err(DecodeError::CorruptData("AC run past end of band"));
+        }
+
+        block[idx] = ac_coeff.wrapping_shl(approx_low as u32);
+
+        idx += 1;
+    }
+
+    Ok(())
+}
+
+/// Reads one correction bit for a coefficient that is already nonzero
+fn refine_coeff<R: BufRead>(
+    coeff: &mut i16,
+    bitreader: &mut BitReader<R>,
+    approx_low: u8,
+) -> Result<(), DecodeError> {
+    let bit = 1 << approx_low;
+
+    if read_bit(bitreader)? && *coeff & bit == 0 {
+        // the bit moves the coefficient away from zero
+        if *coeff >= 0 {
+            *coeff = coeff.wrapping_add(bit);
+        } else {
+            *coeff = coeff.wrapping_sub(bit);
+        }
+    }
+
+    Ok(())
+}
+
+/// Refines the AC coefficients `start..=end` by one bit. Coefficients that
+/// are already nonzero get a correction bit, and zero coefficients can
+/// become +-1 (shifted by `approx_low`).
+pub fn decode_ac_refine<R: BufRead>(
+    block: &mut [i16; 64],
+    ac_huff_tree: &HuffmanTree,
+    bitreader: &mut BitReader<R>,
+    (start, end): (u8, u8),
+    approx_low: u8,
+    eob_run: &mut u16,
+) -> Result<(), DecodeError> {
+    let (start, end) = (start as usize, end as usize);
+
+    let mut idx = start;
+
+    if *eob_run == 0 {
+        while idx <= end {
+            let symbol = ac_huff_tree
+                .read_code(bitreader)
+                .ok_or(DecodeError::CorruptData("invalid AC huffman code"))?;
+
+            let mut run_length = symbol >> 4;
+            let ac_bits = symbol & 0xf;
+
+            let value = match ac_bits {
+                0 if run_length < 15 => {
+                    // the rest of this block is refined below, as part of
+                    // the EOB run
+                    *eob_run = read_eob_run(bitreader, run_length)? + 1;
+                    break;
+                }
+                // ZRL, skip 16 zero coefficients
+                0 => 0,
+                1 if read_bit(bitreader)? => 1 << approx_low,
+                1 => -1 << approx_low,
+                _ => return Err(DecodeError::CorruptData("AC refinement larger than 1")),
+            };
+
+            // skip `run_length` zero coefficients, refining the nonzero
+            // ones in between
+            while idx <= end {
+                let coeff = &mut block[idx];
+
+                if *coeff != 0 {
+                    refine_coeff(coeff, bitreader, approx_low)?;
+                } else if run_length == 0 {
+                    break;
+                } else {
+                    run_length -= 1;
+                }
+
+                idx += 1;
+            }
+
+            if value != 0 {
+                if idx > end {
+                    return Err(DecodeError::CorruptData("AC run past end of band"));
+                }
+
+                block[idx] = value;
+            }
+
+            idx += 1;
+        }
+    }
+
+    if *eob_run > 0 {
+        // only the coefficients that are already nonzero are refined
+        for coeff in &mut block[idx.min(end + 1)..=end] {
+            if *coeff != 0 {
+                refine_coeff(coeff, bitreader, approx_low)?;
+            }
+        }
+
+        *eob_run -= 1;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_eob_run_lengths() {
+        let mut data: &[u8] = &[0b1010_0000];
+        let mut bitreader = BitReader::new(&mut data);
+
+        // EOB0 is a single block, without extra bits
+        assert_eq!(read_eob_run(&mut bitreader, 0).unwrap(), 0);
+        // EOB3 with extra bits 101 is 8 + 5 blocks
+        assert_eq!(read_eob_run(&mut bitreader, 3).unwrap(), 12);
+    }
+
+    #[test]
+    fn decodes_first_ac_scan_with_eob_run() {
+        // 0 codes a coefficient of one bit, 1 an EOB2 run
+        let mut counts = [0; 16];
+        counts[0] = 2;
+        let table = HuffmanTree::from_table(&counts, &[0x01, 0x20]);
+
+        // +1, then EOB2 with extra bits 11
+        let mut data: &[u8] = &[0b0111_1000];
+        let mut bitreader = BitReader::new(&mut data);
+
+        let mut block = [0; 64];
+        let mut eob_run = 0;
+
+        decode_ac_first(&mut block, &table, &mut bitreader, (1, 5), 1, &mut eob_run).unwrap();
+
+        assert_eq!(block[1], 2);
+        assert_eq!(block[2..], [0; 62]);
+        assert_eq!(eob_run, 6);
+
+        // the next block is part of the run, and reads nothing
+        decode_ac_first(&mut block, &table, &mut bitreader, (1, 5), 1, &mut eob_run).unwrap();
+
+        assert_eq!(eob_run, 5);
+        assert_eq!(bitreader.cached_bits(), 3);
+    }
+
+    #[test]
+    fn refines_coefficients_away_from_zero() {
+        let mut data: &[u8] = &[0b1110_1000];
+        let mut bitreader = BitReader::new(&mut data);
+
+        let mut coeffs = [4, -4, 6, 4, i16::MIN];
+
+        for coeff in &mut coeffs {
+            refine_coeff(coeff, &mut bitreader, 1).unwrap();
+        }
+
+        // the third coefficient already has the bit set, the fourth reads
+        // a zero, and the last one wraps around instead of overflowing
+        assert_eq!(coeffs, [6, -6, 6, 4, i16::MAX - 1]);
+    }
+}
@@ -0,0 +1,847 @@
+//! Marker segments and their parsed contents
+
+use std::io::{BufRead, Read};
+
+use crate::bitstream::{read_u16, read_u8, skip_bytes};
+use crate::error::{DecodeError, TableKind};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JpegMarker {
+    StartOfImage,
+    ApplicationDefaultHeader,
+    DefineQuantizationTable,
+    StartOfFrame,
+    StartOfFrameExtended,
+    StartOfFrameProgressive,
+    StartOfFrameLossless,
+    StartOfFrameArithmetic,
+    StartOfFrameProgressiveArithmetic,
+    StartOfFrameLosslessArithmetic,
+    DefineHuffmanTable,
+    DefineArithmeticConditioning,
+    DefineRestartInterval,
+    DefineNumberOfLines,
+    StartOfScan,
+    EndOfImage,
+    PictInfo,
+    AdobeApp14,
+    Comment,
+    AppSeg1,
+    AppSeg2,
+    /// Any other application segment APPn, by its number `n`
+    App(u8),
+}
+
+impl JpegMarker {
+    pub fn segment_name(self) -> &'static str {
+        match self {
+            JpegMarker::StartOfImage => "Start of Image",
+            JpegMarker::ApplicationDefaultHeader => "Application Default Header",
+            JpegMarker::DefineQuantizationTable => "Define Quantization Table",
+            JpegMarker::StartOfFrame => "Start of Frame (Baseline)",
+            JpegMarker::StartOfFrameExtended => "Start of Frame (Extended Sequential)",
+            JpegMarker::StartOfFrameProgressive => "Start of Frame (Progressive)",
+            JpegMarker::StartOfFrameLossless => "Start of Frame (Lossless)",
+            JpegMarker::StartOfFrameArithmetic => {
+                "Start of Frame (Extended Sequential, Arithmetic)"
+            }
+            JpegMarker::StartOfFrameProgressiveArithmetic => {
+                "Start of Frame (Progressive, Arithmetic)"
+            }
+            JpegMarker::StartOfFrameLosslessArithmetic => "Start of Frame (Lossless, Arithmetic)",
+            JpegMarker::DefineHuffmanTable => "Define Huffman Table",
+            JpegMarker::DefineArithmeticConditioning => "Define Arithmetic Coding Conditioning",
+            JpegMarker::DefineRestartInterval => "Define Restart Interval",
+            JpegMarker::DefineNumberOfLines => "Define Number of Lines",
+            JpegMarker::StartOfScan => "Start of Scan",
+            JpegMarker::EndOfImage => "End of Image",
+            JpegMarker::PictInfo => "Picture Info",
+            JpegMarker::AdobeApp14 => "Adobe APP14",
+            JpegMarker::Comment => "Comment",
+            JpegMarker::AppSeg1 => "EXIF Metadata (Application Segment 1)",
+            JpegMarker::AppSeg2 => "ICC color profile, FlashPix",
+            JpegMarker::App(_) => "Application Segment",
+        }
+    }
+
+    /// Whether the marker is followed by a length field and segment data
+    pub fn has_segment(self) -> bool {
+        !matches!(self, JpegMarker::StartOfImage | JpegMarker::EndOfImage)
+    }
+}
+
+impl TryFrom<u16> for JpegMarker {
+    /// The unrecognized marker
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        let [high, low] = value.to_be_bytes();
+
+        if high != 0xff {
+            return Err(value);
+        }
+
+        match low {
+            0xd8 => Ok(JpegMarker::StartOfImage),
+            0xe0 => Ok(JpegMarker::ApplicationDefaultHeader),
+            0xdb => Ok(JpegMarker::DefineQuantizationTable),
+            0xc0 => Ok(JpegMarker::StartOfFrame),
+            0xc1 => Ok(JpegMarker::StartOfFrameExtended),
+            0xc2 => Ok(JpegMarker::StartOfFrameProgressive),
+            0xc3 => Ok(JpegMarker::StartOfFrameLossless),
+            0xc4 => Ok(JpegMarker::DefineHuffmanTable),
+            0xc9 => Ok(JpegMarker::StartOfFrameArithmetic),
+            0xca => Ok(JpegMarker::StartOfFrameProgressiveArithmetic),
+            0xcb => Ok(JpegMarker::StartOfFrameLosslessArithmetic),
+            0xcc => Ok(JpegMarker::DefineArithmeticConditioning),
+            0xdd => Ok(JpegMarker::DefineRestartInterval),
+            0xdc => Ok(JpegMarker::DefineNumberOfLines),
+            0xda => Ok(JpegMarker::StartOfScan),
+            0xd9 => Ok(JpegMarker::EndOfImage),
+            0xec => Ok(JpegMarker::PictInfo),
+            0xee => Ok(JpegMarker::AdobeApp14),
+            0xfe => Ok(JpegMarker::Comment),
+            0xe2 => Ok(JpegMarker::AppSeg2),
+            0xe1 => Ok(JpegMarker::AppSeg1),
+            0xe3..=0xef => Ok(JpegMarker::App(low - 0xe0)),
+            _ => Err(value),
+        }
+    }
+}
+
+/// A marker together with its parsed segment
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub marker: JpegMarker,
+    /// Position of the marker from the start of the data
+    pub offset: u64,
+    /// Value of the length field of the segment, which includes the
+    /// length field itself. Zero for markers without a segment.
+    pub length: u16,
+    pub contents: SegmentContents,
+}
+
+#[derive(Clone, Debug)]
+pub enum SegmentContents {
+    /// Marker without segment data (SOI, EOI)
+    Empty,
+    Jfif(Jfif),
+    Adobe(Adobe),
+    QuantizationTables(Vec<QuantizationTable>),
+    HuffmanTables(Vec<HuffmanTable>),
+    ArithmeticConditioning(Vec<ArithmeticConditioning>),
+    /// Number of MCUs between restart markers, 0 disables them
+    RestartInterval(u16),
+    /// Height of a frame whose header leaves it as 0, defined after the
+    /// first scan
+    NumberOfLines(u16),
+    Frame(Frame),
+    /// Start of scan. The entropy-coded data follows the segment.
+    Scan(Scan),
+    Comment(Vec<u8>),
+    /// Segment whose contents are not interpreted by the decoder
+    Skipped,
+}
+
+/// JFIF header from the APP0 segment
+#[derive(Clone, Debug)]
+pub struct Jfif {
+    pub version: (u8, u8),
+    /// 0 for no units (aspect ratio only), 1 for dots per inch,
+    /// 2 for dots per cm
+    pub units: u8,
+    pub x_density: u16,
+    pub y_density: u16,
+    pub thumbnail_width: u8,
+    pub thumbnail_height: u8,
+}
+
+/// Adobe header from the APP14 segment
+#[derive(Clone, Debug)]
+pub struct Adobe {
+    pub version: u16,
+    pub flags0: u16,
+    pub flags1: u16,
+    /// Color transform of three and four component images. 0 for RGB or
+    /// CMYK, 1 for YCbCr and 2 for YCCK.
+    pub transform: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct QuantizationTable {
+    /// Destination slot of the table
+    pub index: u8,
+    /// Bits per value, either 8 or 16
+    pub precision: u8,
+    /// Values in zigzag order, 8-bit tables are widened
+    pub values: [u16; 64],
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TableClass {
+    Dc,
+    Ac,
+}
+
+#[derive(Clone, Debug)]
+pub struct HuffmanTable {
+    pub class: TableClass,
+    /// Destination slot of the table
+    pub index: u8,
+    /// Number of codes of each length from 1 to 16 bits
+    pub counts: [u8; 16],
+    /// Symbols in order of increasing code length
+    pub symbols: Vec<u8>,
+}
+
+/// Conditioning of the statistics of an arithmetic coding table (DAC)
+#[derive(Clone, Debug)]
+pub struct ArithmeticConditioning {
+    pub class: TableClass,
+    /// Destination slot of the conditioning
+    pub index: u8,
+    /// For DC tables, the lower bound L in the low nibble and the upper
+    /// bound U in the high nibble. For AC tables, the value of Kx.
+    pub value: u8,
+}
+
+/// How the image data of a frame is coded, given by the SOFn marker
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CodingProcess {
+    /// Sequential DCT, every scan codes whole blocks
+    Baseline,
+    /// Sequential DCT with 8 or 12-bit samples and up to four huffman
+    /// tables of each class
+    Extended,
+    /// Progressive DCT, the coefficients of each block are spread over
+    /// several scans
+    Progressive,
+    /// Lossless predictive coding with 2 to 16-bit samples
+    Lossless,
+}
+
+/// Entropy coding of the scans of a frame, given by the SOFn marker
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EntropyCoding {
+    Huffman,
+    Arithmetic,
+}
+
+/// Frame header (SOFn)
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub process: CodingProcess,
+    pub entropy_coding: EntropyCoding,
+    /// Bits per sample
+    pub precision: u8,
+    /// 0 if the height is defined by a DNL segment after the first scan
+    pub height: u16,
+    pub width: u16,
+    pub components: Vec<FrameComponent>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FrameComponent {
+    pub id: u8,
+    /// Horizontal sampling factor
+    pub h_sampling: u8,
+    /// Vertical sampling factor
+    pub v_sampling: u8,
+    /// Quantization table slot used by this component
+    pub quant_table: u8,
+}
+
+/// Scan header (SOS)
+#[derive(Clone, Debug)]
+pub struct Scan {
+    /// Components coded in the scan, in the order they appear in the frame
+    pub components: Vec<ScanComponent>,
+    /// Index of the first DCT coefficient in zigzag order coded in the scan
+    pub spectral_start: u8,
+    /// Index of the last DCT coefficient in zigzag order coded in the scan
+    pub spectral_end: u8,
+    /// Successive approximation bit position of the previous scan of the
+    /// same coefficients, 0 for the first scan
+    pub approx_high: u8,
+    /// Successive approximation bit position (point transform)
+    pub approx_low: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScanComponent {
+    /// Matches the [`FrameComponent::id`] of a component in the frame
+    pub id: u8,
+    /// DC huffman table or arithmetic conditioning slot
+    pub dc_table: u8,
+    /// AC huffman table or arithmetic conditioning slot
+    pub ac_table: u8,
+}
+
+/// Reads the length field of a marker segment, and returns the number
+/// of bytes remaining in the segment after the length field itself.
+pub(crate) fn read_length<R: Read>(reader: &mut R) -> Result<usize, DecodeError> {
+    let len = read_u16(reader)? as usize;
+
+    // The length includes the size of the length field itself
+    len.checked_sub(2).ok_or(DecodeError::InvalidSegment(
+        "segment length shorter than 2 bytes",
+    ))
+}
+
+/// Subtracts `n` bytes from the remaining length of a segment
+fn take_length(len: &mut usize, n: usize) -> Result<(), DecodeError> {
+    *len = len.checked_sub(n).ok_or(DecodeError::InvalidSegment(
+        "segment is longer than its length field",
+    ))?;
+
+    Ok(())
+}
+
+/// Parses the segment data following `marker`. `len` is the number of
+/// bytes in the segment, excluding the length field.
+pub(crate) fn parse_segment<R: BufRead>(
+    reader: &mut R,
+    marker: JpegMarker,
+    len: usize,
+) -> Result<SegmentContents, DecodeError> {
+    Ok(match marker {
+        JpegMarker::StartOfImage | JpegMarker::EndOfImage => SegmentContents::Empty,
+        JpegMarker::ApplicationDefaultHeader => match parse_app0(reader, len)? {
+            Some(jfif) => SegmentContents::Jfif(jfif),
+            None => SegmentContents::Skipped,
+        },
+        JpegMarker::AdobeApp14 => match parse_app14(reader, len)? {
+            Some(adobe) => SegmentContents::Adobe(adobe),
+            None => SegmentContents::Skipped,
+        },
+        JpegMarker::DefineQuantizationTable => {
+            SegmentContents::QuantizationTables(parse_dqt(reader, len)?)
+        }
+        JpegMarker::DefineHuffmanTable => SegmentContents::HuffmanTables(parse_dht(reader, len)?),
+        JpegMarker::DefineRestartInterval => {
+            if len != 2 {
+                return Err(DecodeError::InvalidSegment(
+                    "restart interval segment must be 4 bytes",
+                ));
+            }
+
+            SegmentContents::RestartInterval(read_u16(reader)?)
+        }
+        JpegMarker::DefineNumberOfLines => {
+            if len != 2 {
+                return Err(DecodeError::InvalidSegment(
+                    "number of lines segment must be 4 bytes",
+                ));
+            }
+
+            SegmentContents::NumberOfLines(read_u16(reader)?)
+        }
+        JpegMarker::StartOfFrame => SegmentContents::Frame(parse_sof(
+            reader,
+            len,
+            (CodingProcess::Baseline, EntropyCoding::Huffman),
+        )?),
+        JpegMarker::StartOfFrameExtended => SegmentContents::Frame(parse_sof(
+            reader,
+            len,
+            (CodingProcess::Extended, EntropyCoding::Huffman),
+        )?),
+        JpegMarker::StartOfFrameProgressive => SegmentContents::Frame(parse_sof(
+            reader,
+            len,
+            (CodingProcess::Progressive, EntropyCoding::Huffman),
+        )?),
+        JpegMarker::StartOfFrameLossless => SegmentContents::Frame(parse_sof(
+            reader,
+            len,
+            (CodingProcess::Lossless, EntropyCoding::Huffman),
+        )?),
+        JpegMarker::StartOfFrameArithmetic => SegmentContents::Frame(parse_sof(
+            reader,
+            len,
+            (CodingProcess::Extended, EntropyCoding::Arithmetic),
+        )?),
+        JpegMarker::StartOfFrameProgressiveArithmetic => SegmentContents::Frame(parse_sof(
+            reader,
+            len,
+            (CodingProcess::Progressive, EntropyCoding::Arithmetic),
+        )?),
+        JpegMarker::StartOfFrameLosslessArithmetic => SegmentContents::Frame(parse_sof(
+            reader,
+            len,
+            (CodingProcess::Lossless, EntropyCoding::Arithmetic),
+        )?),
+        JpegMarker::DefineArithmeticConditioning => {
+            SegmentContents::ArithmeticConditioning(parse_dac(reader, len)?)
+        }
+        JpegMarker::StartOfScan => SegmentContents::Scan(parse_sos(reader, len)?),
+        JpegMarker::Comment => {
+            let mut comment = vec![0; len];
+            reader.read_exact(&mut comment)?;
+
+            SegmentContents::Comment(comment)
+        }
+        _ => {
+            skip_bytes(reader, len)?;
+
+            SegmentContents::Skipped
+        }
+    })
+}
+
+/// Returns `None` if the segment is not a JFIF header (e.g. a JFXX extension)
+fn parse_app0<R: Read>(reader: &mut R, len: usize) -> Result<Option<Jfif>, DecodeError> {
+    let mut segment = vec![0; len];
+    reader.read_exact(&mut segment)?;
+
+    // null-terminated identifier, followed by the JFIF fields
+    let fields = match segment.strip_prefix(b"JFIF\0") {
+        Some(fields) => fields,
+        None => return Ok(None),
+    };
+
+    if let [v_maj, v_min, units, dx0, dx1, dy0, dy1, tx, ty, ..] = *fields {
+        Ok(Some(Jfif {
+            version: (v_maj, v_min),
+            units,
+            x_density: u16::from_be_bytes([dx0, dx1]),
+            y_density: u16::from_be_bytes([dy0, dy1]),
+            thumbnail_width: tx,
+            thumbnail_height: ty,
+        }))
+    } else {
+        Err(DecodeError::InvalidSegment("JFIF header is too short"))
+    }
+}
+
+/// Returns `None` if the segment is not an Adobe header
+fn parse_app14<R: Read>(reader: &mut R, len: usize) -> Result<Option<Adobe>, DecodeError> {
+    let mut segment = vec![0; len];
+    reader.read_exact(&mut segment)?;
+
+    let fields = match segment.strip_prefix(b"Adobe") {
+        Some(fields) => fields,
+        None => return Ok(None),
+    };
+
+    if let [v0, v1, f00, f01, f10, f11, transform, ..] = *fields {
+        Ok(Some(Adobe {
+            version: u16::from_be_bytes([v0, v1]),
+            flags0: u16::from_be_bytes([f00, f01]),
+            flags1: u16::from_be_bytes([f10, f11]),
+            transform,
+        }))
+    } else {
+        Err(DecodeError::InvalidSegment("Adobe header is too short"))
+    }
+}
+
+fn parse_dqt<R: Read>(
+    reader: &mut R,
+    mut len: usize,
+) -> Result<Vec<QuantizationTable>, DecodeError> {
+    // one DQT can actually define multiple quant tables
+    // porsche.jpg is an example of this, it defines 2 quantization
+    // tables with one DQT marker
+    let mut tables = Vec::new();
+
+    while len > 0 {
+        let qt_info = read_u8(reader)?;
+        take_length(&mut len, 1)?;
+
+        // bottom 4 bits are the actual dst
+        let dst = qt_info & 0xf;
+
+        if dst > 3 {
+            let kind = TableKind::Quantization;
+            return Err(DecodeError::InvalidTableIndex { kind, index: dst });
+        }
+
+        // if upper 4 bits are 0, 8-bit
+        // if 1, 16-bit (big endian)
+        let precision = match qt_info >> 4 {
+            0 => 8,
+            1 => 16,
+            _ => {
+                return Err(DecodeError::InvalidSegment(
+                    "invalid quantization table precision",
+                ))
+            }
+        };
+
+        let mut values = [0; 64];
+
+        if precision == 8 {
+            let mut buf = [0; 64];
+            take_length(&mut len, 64)?;
+            reader.read_exact(&mut buf)?;
+
+            for (value, byte) in values.iter_mut().zip(buf) {
+                *value = u16::from(byte);
+            }
+        } else {
+            let mut buf = [0; 128];
+            take_length(&mut len, 128)?;
+            reader.read_exact(&mut buf)?;
+
+            for (value, bytes) in values.iter_mut().zip(buf.chunks_exact(2)) {
+                *value = u16::from_be_bytes([bytes[0], bytes[1]]);
+            }
+        }
+
+        tables.push(QuantizationTable {
+            index: dst,
+            precision,
+            values,
+        });
+    }
+
+    Ok(tables)
+}
+
+fn parse_dht<R: Read>(reader: &mut R, mut len: usize) -> Result<Vec<HuffmanTable>, DecodeError> {
+    // Up to 4 huffman tables are allowed in JPEG
+    let mut tables = Vec::new();
+
+    while len > 0 {
+        let ht_info = read_u8(reader)?;
+        take_length(&mut len, 1)?;
+
+        let ht_num = ht_info & 0xf;
+
+        // bit index 4 (5th bit) specifies whether table is for AC/DC
+        // 0 = DC, 1 = AC
+        let class = if (ht_info >> 4) & 1 == 0 {
+            TableClass::Dc
+        } else {
+            TableClass::Ac
+        };
+
+        // ensure bit index 5-7 is 0
+        if ht_info & 0b1110_0000 != 0 {
+            return Err(DecodeError::InvalidSegment("invalid huffman table class"));
+        }
+
+        let kind = match class {
+            TableClass::Dc => TableKind::DcHuffman,
+            TableClass::Ac => TableKind::AcHuffman,
+        };
+
+        if ht_num > 3 {
+            return Err(DecodeError::InvalidTableIndex {
+                kind,
+                index: ht_num,
+            });
+        }
+
+        // read 16 bytes for child node counts for 16 levels of huffman tree
+        let mut counts = [0; 16];
+
+        take_length(&mut len, 16)?;
+        reader.read_exact(&mut counts)?;
+
+        let n_symbs = counts.iter().copied().map(|x| x as usize).sum::<usize>();
+
+        if n_symbs == 0 || n_symbs > 256 {
+            return Err(DecodeError::InvalidSegment(
+                "huffman table must have between 1 and 256 symbols",
+            ));
+        }
+
+        // codes are assigned in increasing order, and there are twice as
+        // many codes available at every length (Kraft inequality). The code
+        // of all 1 bits is reserved (C.2).
+        let mut code = 0u32;
+
+        for (len, &count) in (1..=16).zip(&counts) {
+            code += count as u32;
+
+            let reason = if code > 1 << len {
+                "code lengths are over-subscribed"
+            } else if count > 0 && code == 1 << len {
+                "code of all 1 bits"
+            } else {
+                code <<= 1;
+                continue;
+            };
+
+            return Err(DecodeError::InvalidHuffmanTable {
+                kind,
+                index: ht_num,
+                reason,
+            });
+        }
+
+        let mut symbols = vec![0; n_symbs];
+        take_length(&mut len, n_symbs)?;
+        reader.read_exact(&mut symbols)?;
+
+        tables.push(HuffmanTable {
+            class,
+            index: ht_num,
+            counts,
+            symbols,
+        });
+    }
+
+    Ok(tables)
+}
+
+fn parse_dac<R: Read>(
+    reader: &mut R,
+    len: usize,
+) -> Result<Vec<ArithmeticConditioning>, DecodeError> {
+    if !len.is_multiple_of(2) {
+        return Err(DecodeError::InvalidSegment(
+            "arithmetic conditioning segment length mismatch",
+        ));
+    }
+
+    let mut conditioning = Vec::with_capacity(len / 2);
+
+    let mut buf = [0; 2];
+    for _ in 0..len / 2 {
+        reader.read_exact(&mut buf)?;
+
+        let [info, value] = buf;
+        let index = info & 0xf;
+
+        // same layout as the class and destination of a huffman table
+        let (class, kind) = match info >> 4 {
+            0 => (TableClass::Dc, TableKind::DcConditioning),
+            1 => (TableClass::Ac, TableKind::AcConditioning),
+            _ => {
+                return Err(DecodeError::InvalidSegment(
+                    "invalid arithmetic conditioning class",
+                ))
+            }
+        };
+
+        if index > 3 {
+            return Err(DecodeError::InvalidTableIndex { kind, index });
+        }
+
+        let valid = match class {
+            // L <= U
+            TableClass::Dc => value & 0xf <= value >> 4,
+            TableClass::Ac => (1..=63).contains(&value),
+        };
+
+        if !valid {
+            return Err(DecodeError::InvalidSegment(
+                "invalid arithmetic conditioning value",
+            ));
+        }
+
+        conditioning.push(ArithmeticConditioning {
+            class,
+            index,
+            value,
+        });
+    }
+
+    Ok(conditioning)
+}
+
+fn parse_sof<R: Read>(
+    reader: &mut R,
+    len: usize,
+    (process, entropy_coding): (CodingProcess, EntropyCoding),
+) -> Result<Frame, DecodeError> {
+    // bits per sample
+    let precision = read_u8(reader)?;
+
+    let height = read_u16(reader)?;
+    let width = read_u16(reader)?;
+
+    let num_components = read_u8(reader)?;
+
+    if len != 6 + 3 * num_components as usize {
+        return Err(DecodeError::InvalidSegment("frame header length mismatch"));
+    }
+
+    if width == 0 {
+        return Err(DecodeError::InvalidSegment("image width is zero"));
+    }
+
+    if num_components == 0 {
+        return Err(DecodeError::InvalidSegment("frame has no components"));
+    }
+
+    let mut components = Vec::with_capacity(num_components as usize);
+
+    let mut buf = [0; 3];
+    for _ in 0..num_components {
+        reader.read_exact(&mut buf)?;
+
+        let [id, sampling, quant_table] = buf;
+
+        if quant_table > 3 {
+            let kind = TableKind::Quantization;
+            return Err(DecodeError::InvalidTableIndex {
+                kind,
+                index: quant_table,
+            });
+        }
+
+        let (h_sampling, v_sampling) = (sampling >> 4, sampling & 0xf);
+
+        if !(1..=4).contains(&h_sampling) || !(1..=4).contains(&v_sampling) {
+            return Err(DecodeError::InvalidSegment(
+                "sampling factors must be 1 to 4",
+            ));
+        }
+
+        components.push(FrameComponent {
+            id,
+            h_sampling,
+            v_sampling,
+            quant_table,
+        });
+    }
+
+    Ok(Frame {
+        process,
+        entropy_coding,
+        precision,
+        height,
+        width,
+        components,
+    })
+}
+
+fn parse_sos<R: Read>(reader: &mut R, len: usize) -> Result<Scan, DecodeError> {
+    let num_components = read_u8(reader)?;
+
+    if len != 4 + 2 * num_components as usize {
+        return Err(DecodeError::InvalidSegment("scan header length mismatch"));
+    }
+
+    if !(1..=4).contains(&num_components) {
+        return Err(DecodeError::InvalidSegment(
+            "scan must have 1 to 4 components",
+        ));
+    }
+
+    let mut components = Vec::with_capacity(num_components as usize);
+
+    let mut buf = [0; 2];
+    for _ in 0..num_components {
+        reader.read_exact(&mut buf)?;
+
+        let [id, tables] = buf;
+        let (dc_table, ac_table) = (tables >> 4, tables & 0xf);
+
+        if dc_table > 3 {
+            let kind = TableKind::DcHuffman;
+            return Err(DecodeError::InvalidTableIndex {
+                kind,
+                index: dc_table,
+            });
+        }
+
+        if ac_table > 3 {
+            let kind = TableKind::AcHuffman;
+            return Err(DecodeError::InvalidTableIndex {
+                kind,
+                index: ac_table,
+            });
+        }
+
+        components.push(ScanComponent {
+            id,
+            dc_table,
+            ac_table,
+        });
+    }
+
+    let mut buf = [0; 3];
+    reader.read_exact(&mut buf)?;
+
+    let [spectral_start, spectral_end, approx] = buf;
+
+    Ok(Scan {
+        components,
+        spectral_start,
+        spectral_end,
+        approx_high: approx >> 4,
+        approx_low: approx & 0xf,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// DHT segment data of a DC table in slot 0
+    fn dht(counts: [u8; 16], symbols: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x00];
+        data.extend_from_slice(&counts);
+        data.extend_from_slice(symbols);
+        data
+    }
+
+    fn parse(data: &[u8]) -> Result<Vec<HuffmanTable>, DecodeError> {
+        parse_dht(&mut &data[..], data.len())
+    }
+
+    #[test]
+    fn accepts_valid_table() {
+        let mut counts = [0; 16];
+        counts[1] = 3;
+
+        let tables = parse(&dht(counts, &[0, 1, 2])).unwrap();
+
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].symbols, [0, 1, 2]);
+    }
+
+    #[test]
+    fn rejects_over_subscribed_lengths() {
+        // three codes of 1 bit
+        let mut counts = [0; 16];
+        counts[0] = 3;
+
+        let err = parse(&dht(counts, &[0, 1, 2])).unwrap_err();
+
+        assert!(matches!(
+            err,
+            DecodeError::InvalidHuffmanTable {
+                kind: TableKind::DcHuffman,
+                index: 0,
+                reason: "code lengths are over-subscribed",
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_code_of_all_ones() {
+        // codes 0 and 1
+        let mut counts = [0; 16];
+        counts[0] = 2;
+
+        let err = parse(&dht(counts, &[0, 1])).unwrap_err();
+
+        assert!(matches!(
+            err,
+            DecodeError::InvalidHuffmanTable {
+                reason: "code of all 1 bits",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_all_ones_code_of_16_bits() {
+        // 0, 10, 110, ... up to 15 ones followed by a 16-bit code of all ones
+        let mut counts = [1; 16];
+        counts[15] = 2;
+
+        let symbols: Vec<u8> = (0..17).collect();
+
+        assert!(matches!(
+            parse(&dht(counts, &symbols)),
+            Err(DecodeError::InvalidHuffmanTable { .. })
+        ));
+    }
+}
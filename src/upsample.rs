@@ -0,0 +1,113 @@
+/// Method used to upsample subsampled chroma to the full image size
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Upsampling {
+    /// Replicate every sample, which is the fastest method but makes
+    /// edges look blocky
+    Box,
+    /// Triangle filter, like the "fancy upsampling" of libjpeg. Only used
+    /// for h2v1 and h2v2 subsampling, other ratios fall back to [`Upsampling::Box`].
+    #[default]
+    Fancy,
+}
+
+/// Upsamples a plane of `w` by `h` samples by an integer factor in each
+/// direction, by replicating every sample into an `fx` by `fy` box.
+pub fn upsample_box(plane: &[u16], (w, h): (usize, usize), (fx, fy): (usize, usize)) -> Vec<u16> {
+    let out_w = w * fx;
+
+    let mut out = vec![0; out_w * h * fy];
+
+    for (y, row) in plane.chunks_exact(w).take(h).enumerate() {
+        let out_rows = &mut out[y * fy * out_w..][..fy * out_w];
+
+        let (first, rest) = out_rows.split_at_mut(out_w);
+
+        for (x, &sample) in row.iter().enumerate() {
+            first[x * fx..][..fx].fill(sample);
+        }
+
+        // the other rows of the box are copies of the first one
+        for out_row in rest.chunks_exact_mut(out_w) {
+            out_row.copy_from_slice(first);
+        }
+    }
+
+    out
+}
+
+/// Doubles the width of a plane of `stride` by `rows` samples, using a
+/// triangle filter: every output sample is 3/4 of the nearest input sample
+/// plus 1/4 of the next nearest one. Only the first `w` by `h` samples are
+/// real image data, samples past them are replicated from the edge.
+pub fn upsample_h2v1_fancy(
+    plane: &[u16],
+    (stride, rows): (usize, usize),
+    (w, h): (usize, usize),
+) -> Vec<u16> {
+    let out_stride = 2 * stride;
+
+    let mut out = vec![0; out_stride * rows];
+
+    for y in 0..h {
+        let row = &plane[y * stride..][..w];
+        let out_row = &mut out[y * out_stride..][..2 * w];
+
+        for x in 0..w {
+            let cur = 3 * row[x] as u32;
+            let left = row[x.saturating_sub(1)] as u32;
+            let right = row[(x + 1).min(w - 1)] as u32;
+
+            // alternate rounding bias, like libjpeg
+            out_row[2 * x] = ((cur + left + 1) >> 2) as u16;
+            out_row[2 * x + 1] = ((cur + right + 2) >> 2) as u16;
+        }
+    }
+
+    out
+}
+
+/// Doubles the width and height of a plane of `stride` by `rows` samples,
+/// using a triangle filter in both directions (9/16, 3/16, 3/16 and 1/16
+/// of the four nearest input samples). Only the first `w` by `h` samples
+/// are real image data, samples past them are replicated from the edge.
+pub fn upsample_h2v2_fancy(
+    plane: &[u16],
+    (stride, rows): (usize, usize),
+    (w, h): (usize, usize),
+) -> Vec<u16> {
+    let out_stride = 2 * stride;
+
+    let mut out = vec![0; out_stride * 2 * rows];
+
+    // vertically filtered column sums of the current pair of input rows
+    let mut colsum = vec![0u32; w];
+
+    for y in 0..h {
+        let row = &plane[y * stride..][..w];
+
+        // the output row above the center of the input row is mixed with
+        // the input row above, and the one below with the row below
+        let neighbors = [y.saturating_sub(1), (y + 1).min(h - 1)];
+
+        for (v, neighbor) in neighbors.into_iter().enumerate() {
+            let other = &plane[neighbor * stride..][..w];
+
+            for x in 0..w {
+                colsum[x] = 3 * row[x] as u32 + other[x] as u32;
+            }
+
+            let out_row = &mut out[(2 * y + v) * out_stride..][..2 * w];
+
+            for x in 0..w {
+                let cur = 3 * colsum[x];
+                let left = colsum[x.saturating_sub(1)];
+                let right = colsum[(x + 1).min(w - 1)];
+
+                out_row[2 * x] = ((cur + left + 8) >> 4) as u16;
+                out_row[2 * x + 1] = ((cur + right + 7) >> 4) as u16;
+            }
+        }
+    }
+
+    out
+}